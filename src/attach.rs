@@ -0,0 +1,402 @@
+use std::{
+    fs,
+    io::{self, Read, Write},
+    mem,
+    net::Shutdown,
+    os::unix::{
+        io::RawFd,
+        net::{UnixListener, UnixStream},
+    },
+    sync::{
+        mpsc::{self, Receiver, SyncSender},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use nix::{libc, unistd::read};
+
+use crate::{
+    container::{fetch_container_state, ContainerState},
+    tty::{set_window_size, watch_window_size, write_all, RawTerminal},
+    ROCKER_CONTAINERS_PATH,
+};
+
+// Frames sent over the attach socket: a kind, the length of the payload
+// (big endian u32) and the payload.
+const FRAME_STDIN: u8 = 0;
+const FRAME_STDOUT: u8 = 1;
+const FRAME_STDERR: u8 = 2;
+// Rows and columns of the client's terminal, as big endian u16s.
+const FRAME_RESIZE: u8 = 3;
+// The exit code of the container as a big endian i32, sent before the
+// supervisor disconnects the clients.
+const FRAME_EXIT: u8 = 4;
+
+// Frames waiting to be written to a client. A client which falls this far
+// behind is disconnected rather than holding up the container's output.
+const CLIENT_QUEUE_SIZE: usize = 1024;
+// How long the last frames may take to be written once the container exits.
+const CLIENT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn attach_socket_path(container_id: &str) -> String {
+    format!("{}/{}/attach.sock", *ROCKER_CONTAINERS_PATH, container_id)
+}
+
+// Listens on the container's attach socket in the supervisor. The output of
+// the container is sent to every attached client, and their input goes to the
+// container's stdin (or pty).
+pub struct AttachServer {
+    path: String,
+    tty: bool,
+    clients: Mutex<Vec<Client>>,
+    // The pty master or the write end of the container's stdin pipe. None
+    // when the container takes no input or isn't running.
+    input: Mutex<Option<RawFd>>,
+}
+
+// The output for an attached client is queued and written by a thread of its
+// own, so that a client which doesn't read can't block the others.
+struct Client {
+    frames: SyncSender<Vec<u8>>,
+    stream: UnixStream,
+    // Closed when the writer is done.
+    written: Receiver<()>,
+}
+
+impl Client {
+    fn new(stream: UnixStream) -> Result<Self> {
+        let (frames, queue) = mpsc::sync_channel::<Vec<u8>>(CLIENT_QUEUE_SIZE);
+        let (done, written) = mpsc::channel();
+        let mut writer = stream.try_clone()?;
+        thread::spawn(move || {
+            for frame in queue {
+                if writer.write_all(&frame).is_err() {
+                    break;
+                }
+            }
+            drop(done);
+        });
+        Ok(Client {
+            frames: frames,
+            stream: stream,
+            written: written,
+        })
+    }
+
+    // Waits a while for the queued frames to be written, which also wakes up
+    // a writer stuck on a client that doesn't read.
+    fn close(self) {
+        drop(self.frames);
+        let _ = self.written.recv_timeout(CLIENT_CLOSE_TIMEOUT);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl AttachServer {
+    pub fn listen(container_id: &str, tty: bool) -> Result<Arc<Self>> {
+        let path = attach_socket_path(container_id);
+        // Left behind by the previous run of a restarted container.
+        let _ = fs::remove_file(&path);
+        let listener =
+            UnixListener::bind(&path).with_context(|| format!("Failed to listen on {}", path))?;
+
+        let server = Arc::new(AttachServer {
+            path: path,
+            tty: tty,
+            clients: Mutex::new(Vec::new()),
+            input: Mutex::new(None),
+        });
+        let s = server.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(stream) = stream {
+                    let _ = s.accept(stream);
+                }
+            }
+        });
+        Ok(server)
+    }
+
+    // The client is told first whether the container has a tty.
+    fn accept(self: &Arc<Self>, mut stream: UnixStream) -> Result<()> {
+        stream.write_all(&[self.tty as u8])?;
+        let client = Client::new(stream.try_clone()?)?;
+        self.clients.lock().unwrap().push(client);
+
+        let server = self.clone();
+        thread::spawn(move || server.forward_input(stream));
+        Ok(())
+    }
+
+    fn forward_input(&self, mut stream: UnixStream) {
+        while let Ok((kind, payload)) = read_frame(&mut stream) {
+            let input = match *self.input.lock().unwrap() {
+                Some(input) => input,
+                None => continue,
+            };
+            match kind {
+                FRAME_STDIN => {
+                    let _ = write_all(input, &payload);
+                }
+                FRAME_RESIZE if self.tty && payload.len() == 4 => {
+                    let rows = u16::from_be_bytes([payload[0], payload[1]]);
+                    let cols = u16::from_be_bytes([payload[2], payload[3]]);
+                    let _ = set_window_size(input, rows, cols);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn set_input(&self, input: Option<RawFd>) {
+        *self.input.lock().unwrap() = input;
+    }
+
+    // Clients which have gone away or whose queue is full are disconnected.
+    pub fn broadcast(&self, stream: &str, data: &[u8]) {
+        let kind = if stream == "stderr" {
+            FRAME_STDERR
+        } else {
+            FRAME_STDOUT
+        };
+        let frame = encode_frame(kind, data);
+        self.clients.lock().unwrap().retain(|client| {
+            let queued = client.frames.try_send(frame.clone()).is_ok();
+            if !queued {
+                let _ = client.stream.shutdown(Shutdown::Both);
+            }
+            queued
+        });
+    }
+
+    // Disconnects every client once the container has exited for good, after
+    // the frames queued for it have been written.
+    pub fn close(&self, exit_code: Option<i32>) {
+        let _ = fs::remove_file(&self.path);
+        let clients = mem::take(&mut *self.clients.lock().unwrap());
+        for client in clients {
+            if let Some(exit_code) = exit_code {
+                let _ = client
+                    .frames
+                    .try_send(encode_frame(FRAME_EXIT, &exit_code.to_be_bytes()));
+            }
+            client.close();
+        }
+    }
+}
+
+fn encode_frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + payload.len());
+    frame.push(kind);
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn write_frame(mut stream: &UnixStream, kind: u8, payload: &[u8]) -> io::Result<()> {
+    // Written at once so that frames from different threads don't interleave.
+    stream.write_all(&encode_frame(kind, payload))
+}
+
+fn read_frame(mut stream: &UnixStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    stream.read_exact(&mut header)?;
+    let len = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+    let mut payload = vec![0u8; len as usize];
+    stream.read_exact(&mut payload)?;
+    Ok((header[0], payload))
+}
+
+// Parses a detach key sequence such as "ctrl-p,ctrl-q", the same way as
+// Docker: each key is a single character or "ctrl-" followed by one of a-z,
+// @, [, \, ], ^ and _.
+pub fn parse_detach_keys(keys: &str) -> Result<Vec<u8>> {
+    let mut sequence = Vec::new();
+    for key in keys.split(',') {
+        let byte = match key.strip_prefix("ctrl-") {
+            Some(c) if c.len() == 1 => match c.as_bytes()[0] {
+                c @ b'a'..=b'z' => c - b'a' + 1,
+                c @ b'@'..=b'_' if !c.is_ascii_uppercase() => c - b'@',
+                _ => return Err(anyhow!("Invalid detach key: {}", key)),
+            },
+            _ if key.len() == 1 => key.as_bytes()[0],
+            _ => return Err(anyhow!("Invalid detach key: {}", key)),
+        };
+        sequence.push(byte);
+    }
+    Ok(sequence)
+}
+
+enum AttachEvent {
+    Detached,
+    Exited(i32),
+    Disconnected,
+}
+
+// Connects the caller's stdio to the container through its supervisor until
+// the container exits or the detach keys are typed. Returns the exit code of
+// the container, or None if the caller has detached.
+pub fn attach_container(container_id: &str, detach_keys: &[u8]) -> Result<Option<i32>> {
+    match fetch_container_state(container_id)? {
+        Some(ContainerState::Running) => {}
+        Some(ContainerState::Paused) => {
+            return Err(anyhow!(
+                "You cannot attach to a paused container, unpause it first"
+            ))
+        }
+        Some(_) => return Err(anyhow!("container is not running: {}", container_id)),
+        None => return Err(anyhow!("container not found: {}", container_id)),
+    }
+
+    let mut stream = UnixStream::connect(attach_socket_path(container_id))
+        .with_context(|| format!("Failed to attach to container: {}", container_id))?;
+    let mut tty = [0u8; 1];
+    stream.read_exact(&mut tty)?;
+    let tty = tty[0] == 1;
+
+    let raw_terminal = if tty {
+        RawTerminal::new(libc::STDIN_FILENO)?
+    } else {
+        None
+    };
+    let writer = Arc::new(Mutex::new(stream.try_clone()?));
+    if tty {
+        let writer = writer.clone();
+        watch_window_size(move |size| {
+            let mut payload = size.ws_row.to_be_bytes().to_vec();
+            payload.extend_from_slice(&size.ws_col.to_be_bytes());
+            write_frame(&writer.lock().unwrap(), FRAME_RESIZE, &payload)?;
+            Ok(())
+        })?;
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let detach_tx = tx.clone();
+    let detach_keys = detach_keys.to_vec();
+    thread::spawn(move || {
+        if forward_stdin(&writer, &detach_keys) {
+            let _ = detach_tx.send(AttachEvent::Detached);
+        }
+    });
+    thread::spawn(move || {
+        let _ = tx.send(relay_output(&stream));
+    });
+
+    let event = rx.recv()?;
+    drop(raw_terminal);
+    match event {
+        AttachEvent::Detached => Ok(None),
+        AttachEvent::Exited(exit_code) => Ok(Some(exit_code)),
+        AttachEvent::Disconnected => Err(anyhow!("Lost the connection to the container")),
+    }
+}
+
+// Sends the caller's stdin to the container until it reaches EOF, or until
+// the detach keys are typed, in which case true is returned. Keys which
+// begin the detach sequence are held back until it's clear whether they
+// complete it.
+fn forward_stdin(writer: &Mutex<UnixStream>, detach_keys: &[u8]) -> bool {
+    let mut buf = [0u8; 4096];
+    let mut matched = 0;
+    loop {
+        let n = match read(libc::STDIN_FILENO, &mut buf) {
+            Ok(0) | Err(_) => return false,
+            Ok(n) => n,
+        };
+
+        let mut input = Vec::with_capacity(n);
+        for b in &buf[..n] {
+            if *b == detach_keys[matched] {
+                matched += 1;
+                if matched == detach_keys.len() {
+                    return true;
+                }
+                continue;
+            }
+            input.extend_from_slice(&detach_keys[..matched]);
+            matched = 0;
+            if *b == detach_keys[0] {
+                matched = 1;
+            } else {
+                input.push(*b);
+            }
+        }
+
+        if !input.is_empty() && write_frame(&writer.lock().unwrap(), FRAME_STDIN, &input).is_err() {
+            return false;
+        }
+    }
+}
+
+fn relay_output(stream: &UnixStream) -> AttachEvent {
+    loop {
+        let (kind, payload) = match read_frame(stream) {
+            Ok(frame) => frame,
+            Err(_) => return AttachEvent::Disconnected,
+        };
+        match kind {
+            FRAME_STDOUT => {
+                let _ = write_all(libc::STDOUT_FILENO, &payload);
+            }
+            FRAME_STDERR => {
+                let _ = write_all(libc::STDERR_FILENO, &payload);
+            }
+            FRAME_EXIT if payload.len() == 4 => {
+                let exit_code =
+                    i32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]);
+                return AttachEvent::Exited(exit_code);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_broadcast_to_client_which_does_not_read() {
+        let (stuck, _stuck_peer) = UnixStream::pair().unwrap();
+        let (reading, mut reading_peer) = UnixStream::pair().unwrap();
+        let server = AttachServer {
+            path: String::new(),
+            tty: false,
+            clients: Mutex::new(vec![
+                Client::new(stuck).unwrap(),
+                Client::new(reading).unwrap(),
+            ]),
+            input: Mutex::new(None),
+        };
+        let received = Arc::new(AtomicUsize::new(0));
+        let r = received.clone();
+        let reader = thread::spawn(move || {
+            while let Ok((kind, _)) = read_frame(&mut reading_peer) {
+                if kind == FRAME_EXIT {
+                    return true;
+                }
+                r.fetch_add(1, Ordering::SeqCst);
+            }
+            false
+        });
+
+        // More than the queue and the socket buffer can hold, sent no faster
+        // than the other client reads.
+        let count = CLIENT_QUEUE_SIZE * 4;
+        for i in 0..count {
+            server.broadcast("stdout", &[0u8; 4096]);
+            while i + 1 - received.load(Ordering::SeqCst) > CLIENT_QUEUE_SIZE / 2 {
+                thread::yield_now();
+            }
+        }
+        assert_eq!(server.clients.lock().unwrap().len(), 1);
+
+        server.close(Some(0));
+        assert!(reader.join().unwrap());
+        assert_eq!(received.load(Ordering::SeqCst), count);
+    }
+}
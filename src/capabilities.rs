@@ -0,0 +1,219 @@
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use nix::libc;
+
+// Indexed by capability number.
+// https://github.com/torvalds/linux/blob/master/include/uapi/linux/capability.h
+const CAPABILITIES: [&str; 41] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_DAC_READ_SEARCH",
+    "CAP_FOWNER",
+    "CAP_FSETID",
+    "CAP_KILL",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETPCAP",
+    "CAP_LINUX_IMMUTABLE",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_NET_BROADCAST",
+    "CAP_NET_ADMIN",
+    "CAP_NET_RAW",
+    "CAP_IPC_LOCK",
+    "CAP_IPC_OWNER",
+    "CAP_SYS_MODULE",
+    "CAP_SYS_RAWIO",
+    "CAP_SYS_CHROOT",
+    "CAP_SYS_PTRACE",
+    "CAP_SYS_PACCT",
+    "CAP_SYS_ADMIN",
+    "CAP_SYS_BOOT",
+    "CAP_SYS_NICE",
+    "CAP_SYS_RESOURCE",
+    "CAP_SYS_TIME",
+    "CAP_SYS_TTY_CONFIG",
+    "CAP_MKNOD",
+    "CAP_LEASE",
+    "CAP_AUDIT_WRITE",
+    "CAP_AUDIT_CONTROL",
+    "CAP_SETFCAP",
+    "CAP_MAC_OVERRIDE",
+    "CAP_MAC_ADMIN",
+    "CAP_SYSLOG",
+    "CAP_WAKE_ALARM",
+    "CAP_BLOCK_SUSPEND",
+    "CAP_AUDIT_READ",
+    "CAP_PERFMON",
+    "CAP_BPF",
+    "CAP_CHECKPOINT_RESTORE",
+];
+
+// The same capabilities as Docker gives to containers by default.
+const DEFAULT_CAPABILITIES: [&str; 14] = [
+    "CAP_CHOWN",
+    "CAP_DAC_OVERRIDE",
+    "CAP_FSETID",
+    "CAP_FOWNER",
+    "CAP_MKNOD",
+    "CAP_NET_RAW",
+    "CAP_SETGID",
+    "CAP_SETUID",
+    "CAP_SETFCAP",
+    "CAP_SETPCAP",
+    "CAP_NET_BIND_SERVICE",
+    "CAP_SYS_CHROOT",
+    "CAP_KILL",
+    "CAP_AUDIT_WRITE",
+];
+
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+pub fn default_capabilities() -> Vec<String> {
+    DEFAULT_CAPABILITIES.iter().map(|c| c.to_string()).collect()
+}
+
+// Applies `--cap-add`s and `--cap-drop`s to `base`. Names are accepted with
+// or without the "CAP_" prefix, and "ALL" stands for every capability.
+pub fn resolve_capabilities(
+    base: &[String],
+    cap_add: &[String],
+    cap_drop: &[String],
+    privileged: bool,
+) -> Result<Vec<String>> {
+    let all: Vec<String> = CAPABILITIES.iter().map(|c| c.to_string()).collect();
+    if privileged {
+        return Ok(all);
+    }
+
+    let cap_add = normalize_names(cap_add)?;
+    let cap_drop = normalize_names(cap_drop)?;
+    let mut caps = if cap_add.iter().any(|c| c == "ALL") {
+        all
+    } else if cap_drop.iter().any(|c| c == "ALL") {
+        Vec::new()
+    } else {
+        base.to_vec()
+    };
+
+    for cap in cap_add.iter().filter(|c| *c != "ALL") {
+        if !caps.contains(cap) {
+            caps.push(cap.clone());
+        }
+    }
+    caps.retain(|c| !cap_drop.contains(c));
+    Ok(caps)
+}
+
+fn normalize_names(names: &[String]) -> Result<Vec<String>> {
+    let mut normalized = Vec::new();
+    for name in names {
+        let name = name.to_uppercase();
+        if name == "ALL" {
+            normalized.push(name);
+            continue;
+        }
+
+        let name = if name.starts_with("CAP_") {
+            name
+        } else {
+            format!("CAP_{}", name)
+        };
+        if !CAPABILITIES.contains(&name.as_str()) {
+            return Err(anyhow!("Unknown capability: {}", name));
+        }
+        normalized.push(name);
+    }
+    Ok(normalized)
+}
+
+// Removes every capability but `caps` from the bounding set, so that they
+// can't be regained even by executing a setuid binary. Needs CAP_SETPCAP, so
+// it must be called before switching to another user.
+pub fn drop_bounding_capabilities(caps: &[String]) -> Result<()> {
+    for (cap, name) in CAPABILITIES.iter().enumerate().take(last_capability() + 1) {
+        if caps.iter().any(|c| c == name) {
+            continue;
+        }
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) } != 0 {
+            return Err(anyhow!(
+                "Failed to drop {}: {}",
+                name,
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Limits the effective, permitted and inheritable sets to `caps`. A user
+// other than root has already lost its permitted capabilities when it was
+// switched to, so this can only reduce them.
+pub fn set_capabilities(caps: &[String]) -> Result<()> {
+    let mut mask = [0u32; 2];
+    for (cap, name) in CAPABILITIES.iter().enumerate() {
+        if caps.iter().any(|c| c == name) {
+            mask[cap / 32] |= 1 << (cap % 32);
+        }
+    }
+
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    let mut data = [CapUserData::default(); 2];
+    if unsafe { libc::syscall(libc::SYS_capget, &mut header, data.as_mut_ptr()) } != 0 {
+        return Err(anyhow!(
+            "Failed to get capabilities: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    for (i, d) in data.iter_mut().enumerate() {
+        let caps = mask[i] & d.permitted;
+        d.effective = caps;
+        d.permitted = caps;
+        d.inheritable = caps;
+    }
+    if unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) } != 0 {
+        return Err(anyhow!(
+            "Failed to set capabilities: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    // Ambient capabilities would be kept across execve().
+    unsafe {
+        libc::prctl(
+            libc::PR_CAP_AMBIENT,
+            libc::PR_CAP_AMBIENT_CLEAR_ALL,
+            0,
+            0,
+            0,
+        )
+    };
+    Ok(())
+}
+
+// Older kernels don't know about the newest capabilities.
+fn last_capability() -> usize {
+    fs::read_to_string("/proc/sys/kernel/cap_last_cap")
+        .ok()
+        .and_then(|s| s.trim().parse().ok())
+        .unwrap_or(CAPABILITIES.len() - 1)
+        .min(CAPABILITIES.len() - 1)
+}
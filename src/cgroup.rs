@@ -1,4 +1,10 @@
-use std::{fs::OpenOptions, io::Write, path::Path, time::Duration};
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    thread,
+    time::Duration,
+};
 
 use anyhow::{anyhow, Result};
 use dbus::{
@@ -6,8 +12,11 @@ use dbus::{
     blocking::Connection,
 };
 
+use nix::unistd::getuid;
 use regex::Regex;
 
+use crate::rootless::is_rootless;
+
 pub fn create_cgroup(
     container_id: &str,
     target_pid: u32,
@@ -15,7 +24,7 @@ pub fn create_cgroup(
     cpus: Option<f32>,
     pids: Option<i32>,
 ) -> Result<()> {
-    let conn = Connection::new_system()?;
+    let conn = connect_to_systemd()?;
     let proxy = conn.with_proxy(
         "org.freedesktop.systemd1",
         "/org/freedesktop/systemd1",
@@ -24,21 +33,174 @@ pub fn create_cgroup(
 
     use super::dbus_systemd::OrgFreedesktopSystemd1Manager;
 
-    let properties = build_properties(target_pid, mem, cpus, pids, container_id)?;
-    let _r = proxy.start_transient_unit(
-        &format!("rocker-{}.scope", container_id),
-        "replace",
-        properties,
-        Vec::new(),
-    )?;
+    // The scope of a restarted container may still be around for a moment, or
+    // be left failed if it was killed for running out of memory.
+    let name = format!("rocker-{}.scope", container_id);
+    let _ = proxy.reset_failed_unit(&name);
+    let mut retries = 0;
+    loop {
+        let mut properties = build_properties(target_pid, mem.clone(), cpus, pids, container_id)?;
+        if is_rootless() {
+            properties.push(("Slice", Variant(Box::new("app.slice".to_string()))));
+        }
+        match proxy.start_transient_unit(&name, "replace", properties, Vec::new()) {
+            Ok(_) => return Ok(()),
+            Err(_) if retries < 10 => {
+                retries += 1;
+                thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+// Sends a signal to every process in the container's scope.
+pub fn kill_cgroup(container_id: &str, signal: i32) -> Result<()> {
+    let conn = connect_to_systemd()?;
+    let proxy = conn.with_proxy(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        Duration::new(5, 0),
+    );
+
+    use super::dbus_systemd::OrgFreedesktopSystemd1Manager;
+
+    proxy.kill_unit(&format!("rocker-{}.scope", container_id), "all", signal)?;
 
     Ok(())
 }
 
+// The pids (in the host's PID namespace) of every process in the container's
+// scope, read from the cgroup if systemd can't be asked.
+pub fn fetch_cgroup_pids(container_id: &str) -> Result<Vec<u32>> {
+    let conn = connect_to_systemd()?;
+    let proxy = conn.with_proxy(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        Duration::new(5, 0),
+    );
+
+    use super::dbus_systemd::OrgFreedesktopSystemd1Manager;
+
+    if let Ok(processes) = proxy.get_unit_processes(&format!("rocker-{}.scope", container_id)) {
+        return Ok(processes.into_iter().map(|(_, pid, _)| pid).collect());
+    }
+
+    let path = if is_cgroup_v2() {
+        fetch_cgroup_v2_scope_path(container_id)
+    } else {
+        fetch_cgroup_v1_resource_path("pids", container_id)
+    };
+    let procs = fs::read_to_string(format!("{}/cgroup.procs", path))?;
+    Ok(procs
+        .lines()
+        .filter_map(|pid| pid.trim().parse().ok())
+        .collect())
+}
+
+// Stops every process in the container's scope with the cgroup freezer.
+// systemd can only do it since v246, so the freezer is used directly otherwise.
+pub fn freeze_cgroup(container_id: &str) -> Result<()> {
+    let conn = connect_to_systemd()?;
+    let proxy = conn.with_proxy(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        Duration::new(5, 0),
+    );
+
+    use super::dbus_systemd::OrgFreedesktopSystemd1Manager;
+
+    if proxy
+        .freeze_unit(&format!("rocker-{}.scope", container_id))
+        .is_ok()
+    {
+        return Ok(());
+    }
+    set_freezer_state(container_id, true)
+}
+
+pub fn thaw_cgroup(container_id: &str) -> Result<()> {
+    let conn = connect_to_systemd()?;
+    let proxy = conn.with_proxy(
+        "org.freedesktop.systemd1",
+        "/org/freedesktop/systemd1",
+        Duration::new(5, 0),
+    );
+
+    use super::dbus_systemd::OrgFreedesktopSystemd1Manager;
+
+    if proxy
+        .thaw_unit(&format!("rocker-{}.scope", container_id))
+        .is_ok()
+    {
+        return Ok(());
+    }
+    set_freezer_state(container_id, false)
+}
+
+// cgroup v2 has cgroup.freeze in every cgroup. With cgroup v1, systemd doesn't
+// create the scope in the freezer hierarchy, so it's created here and the
+// container's processes are moved into it.
+fn set_freezer_state(container_id: &str, frozen: bool) -> Result<()> {
+    if is_cgroup_v2() {
+        let path = fetch_cgroup_v2_scope_path(container_id);
+        fs::write(
+            format!("{}/cgroup.freeze", path),
+            if frozen { "1" } else { "0" },
+        )?;
+        return wait_for_freezer_state(&format!("{}/cgroup.events", path), |events| {
+            events
+                .lines()
+                .any(|l| l == if frozen { "frozen 1" } else { "frozen 0" })
+        });
+    }
+
+    let path = format!(
+        "/sys/fs/cgroup/freezer/system.slice/rocker-{}.scope",
+        container_id
+    );
+    if frozen {
+        fs::create_dir_all(&path)?;
+        let pids_path = format!(
+            "/sys/fs/cgroup/pids/system.slice/rocker-{}.scope/cgroup.procs",
+            container_id
+        );
+        for pid in fs::read_to_string(pids_path)?.lines() {
+            // The process may have exited in the meantime.
+            let _ = fs::write(format!("{}/cgroup.procs", path), pid);
+        }
+    }
+    let state = if frozen { "FROZEN" } else { "THAWED" };
+    fs::write(format!("{}/freezer.state", path), state)?;
+    wait_for_freezer_state(&format!("{}/freezer.state", path), |s| s.trim() == state)
+}
+
+fn wait_for_freezer_state(path: &str, is_done: impl Fn(&str) -> bool) -> Result<()> {
+    for _ in 0..50 {
+        if is_done(&fs::read_to_string(path)?) {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    Err(anyhow!(
+        "Timed out waiting for the cgroup freezer: {}",
+        path
+    ))
+}
+
+// Rootless containers are managed by the user's systemd instance.
+fn connect_to_systemd() -> Result<Connection> {
+    if is_rootless() {
+        Ok(Connection::new_session()?)
+    } else {
+        Ok(Connection::new_system()?)
+    }
+}
+
 pub fn add_process_to_cgroup(container_id: &str, pid: u32) -> Result<()> {
     // Check if using cgroup v2.
     // https://github.com/opencontainers/runc/blob/master/docs/cgroup-v2.md
-    if Path::new("/sys/fs/cgroup/cgroup.controllers").exists() {
+    if is_cgroup_v2() {
         let path = fetch_cgroup_v2_scope_path(container_id);
         let mut file = OpenOptions::new()
             .write(true)
@@ -59,23 +221,132 @@ pub fn add_process_to_cgroup(container_id: &str, pid: u32) -> Result<()> {
     Ok(())
 }
 
+fn is_cgroup_v2() -> bool {
+    Path::new("/sys/fs/cgroup/cgroup.controllers").exists()
+}
+
 fn fetch_cgroup_v2_scope_path(container_id: &str) -> String {
+    if is_rootless() {
+        let uid = getuid();
+        return format!(
+            "/sys/fs/cgroup/user.slice/user-{}.slice/user@{}.service/app.slice/rocker-{}.scope",
+            uid, uid, container_id
+        );
+    }
     format!("/sys/fs/cgroup/system.slice/rocker-{}.scope", container_id)
 }
 
+// The cgroups of the container's scope: one with cgroup v2, or one per
+// resource with cgroup v1.
+pub fn fetch_cgroup_paths(container_id: &str) -> Vec<String> {
+    if is_cgroup_v2() {
+        vec![fetch_cgroup_v2_scope_path(container_id)]
+    } else {
+        fetch_cgourp_v1_resource_paths(container_id)
+    }
+}
+
 fn fetch_cgourp_v1_resource_paths(container_id: &str) -> Vec<String> {
     // Rocker only supports "cpu", "memory", and "pids".
     let resources = ["cpu", "memory", "pids"];
     let mut paths: Vec<String> = Vec::new();
     for r in resources.iter() {
-        paths.push(format!(
-            "/sys/fs/cgroup/{}/system.slice/rocker-{}.scope",
-            r, container_id
-        ));
+        paths.push(fetch_cgroup_v1_resource_path(r, container_id));
     }
     paths
 }
 
+fn fetch_cgroup_v1_resource_path(resource: &str, container_id: &str) -> String {
+    format!(
+        "/sys/fs/cgroup/{}/system.slice/rocker-{}.scope",
+        resource, container_id
+    )
+}
+
+// Resource usage of the container's scope.
+pub struct CgroupStats {
+    pub cpu_usage_nsec: u64,
+    pub memory_usage: u64,
+    // None if there's no limit.
+    pub memory_limit: Option<u64>,
+    pub pids: u64,
+    pub block_read: u64,
+    pub block_write: u64,
+}
+
+pub fn read_cgroup_stats(container_id: &str) -> Result<CgroupStats> {
+    if is_cgroup_v2() {
+        let path = fetch_cgroup_v2_scope_path(container_id);
+        let cpu_stat = fs::read_to_string(format!("{}/cpu.stat", path)).unwrap_or_default();
+        let io_stat = fs::read_to_string(format!("{}/io.stat", path)).unwrap_or_default();
+        return Ok(CgroupStats {
+            cpu_usage_nsec: find_stat(&cpu_stat, "usage_usec") * 1000,
+            memory_usage: read_number(&format!("{}/memory.current", path))?,
+            memory_limit: read_number(&format!("{}/memory.max", path)).ok(),
+            pids: read_number(&format!("{}/pids.current", path)).unwrap_or(0),
+            // "8:0 rbytes=... wbytes=... rios=... ..." per device
+            block_read: sum_io_stat(&io_stat, "rbytes="),
+            block_write: sum_io_stat(&io_stat, "wbytes="),
+        });
+    }
+
+    let io_service_bytes = fs::read_to_string(format!(
+        "{}/blkio.throttle.io_service_bytes",
+        fetch_cgroup_v1_resource_path("blkio", container_id)
+    ))
+    .unwrap_or_default();
+    // "8:0 Read ..." and "8:0 Write ..." per device
+    let sum_blkio = |op: &str| -> u64 {
+        io_service_bytes
+            .lines()
+            .map(|line| line.split_whitespace().collect::<Vec<&str>>())
+            .filter(|fields| fields.len() == 3 && fields[1] == op)
+            .filter_map(|fields| fields[2].parse::<u64>().ok())
+            .sum()
+    };
+    let memory_path = fetch_cgroup_v1_resource_path("memory", container_id);
+    Ok(CgroupStats {
+        cpu_usage_nsec: read_number(&format!(
+            "{}/cpuacct.usage",
+            fetch_cgroup_v1_resource_path("cpu", container_id)
+        ))
+        .unwrap_or(0),
+        memory_usage: read_number(&format!("{}/memory.usage_in_bytes", memory_path))?,
+        // A cgroup without a limit has a huge one instead.
+        memory_limit: read_number(&format!("{}/memory.limit_in_bytes", memory_path))
+            .ok()
+            .filter(|limit| *limit < i64::MAX as u64 / 2),
+        pids: read_number(&format!(
+            "{}/pids.current",
+            fetch_cgroup_v1_resource_path("pids", container_id)
+        ))
+        .unwrap_or(0),
+        block_read: sum_blkio("Read"),
+        block_write: sum_blkio("Write"),
+    })
+}
+
+fn read_number(path: &str) -> Result<u64> {
+    Ok(fs::read_to_string(path)?.trim().parse()?)
+}
+
+// Finds "key value" in a flat keyed file such as cpu.stat.
+fn find_stat(stat: &str, key: &str) -> u64 {
+    stat.lines()
+        .filter_map(|line| line.strip_prefix(key))
+        .filter_map(|value| value.trim().parse().ok())
+        .next()
+        .unwrap_or(0)
+}
+
+fn sum_io_stat(io_stat: &str, key: &str) -> u64 {
+    io_stat
+        .split_whitespace()
+        .filter_map(|field| field.strip_prefix(key))
+        .filter_map(|value| value.parse::<u64>().ok())
+        .sum()
+}
+
 fn build_properties(
     target_pid: u32,
     mem: Option<String>,
@@ -1,104 +1,831 @@
-use super::{ROCKER_CONTAINERS_PATH, ROCKER_DB_PATH, ROCKER_IMAGES_PATH, ROCKER_NETNS_PATH};
+use super::{ROCKER_CONTAINERS_PATH, ROCKER_NETNS_PATH};
 use std::{
+    convert::TryFrom,
     ffi::CString,
-    fs::{self, create_dir_all},
+    fs::{self, create_dir_all, OpenOptions},
+    os::unix::{
+        fs::{symlink, PermissionsExt},
+        io::{AsRawFd, RawFd},
+    },
     path::Path,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context, Result};
-use dkregistry::v2::manifest::ManifestSchema2;
+use chrono::Utc;
 use hex::encode;
 use nix::{
     fcntl::{open, OFlag},
-    mount::{umount, MsFlags},
-    sched::{clone, setns, CloneFlags},
-    sys::{signal::Signal, wait::waitpid},
-    unistd::{chdir, chroot, execv},
+    libc,
+    mount::{umount2, MntFlags, MsFlags},
+    sched::{clone, setns, unshare, CloneFlags},
+    sys::{
+        signal::{kill, Signal},
+        socket::{socketpair, AddressFamily, SockFlag, SockType},
+        stat::{makedev, mknod, Mode, SFlag},
+        wait::{waitpid, WaitStatus},
+    },
+    unistd::{chdir, close, dup2, execve, getpid, pipe, pipe2, pivot_root, read, setsid, Pid},
 };
 use rand::Rng;
 
 use crate::{
-    cgroup::{add_process_to_cgroup, create_cgroup},
+    attach::AttachServer,
+    capabilities::{
+        default_capabilities, drop_bounding_capabilities, resolve_capabilities, set_capabilities,
+    },
+    cgroup::{add_process_to_cgroup, create_cgroup, freeze_cgroup, kill_cgroup, thaw_cgroup},
     db::{
-        container_commands_key, container_image_hashes_key, container_pids_key,
-        downloaded_images_key, used_ip_addresses_key, veth_ip_addresses_key,
+        container_auto_removes_key, container_capabilities_key, container_commands_key,
+        container_created_at_key, container_envs_key, container_exit_codes_key,
+        container_finished_at_key, container_image_hashes_key, container_manual_stops_key,
+        container_mounts_key, container_pids_key, container_restart_counts_key,
+        container_restart_policies_key, container_seccomp_profiles_key, container_started_at_key,
+        container_states_key, container_supervisor_pids_key, container_users_key,
+        container_working_dirs_key, downloaded_images_key, open_db, used_ip_addresses_key,
+        veth_ip_addresses_key,
     },
-    image::download_image_if_needed,
-    network::{delete_netns, setup_netns, setup_veths},
+    etc_files::{create_etc_files, EtcFilesOptions},
+    fork::fork_fn,
+    image::{download_image_if_needed, fetch_image_layers, load_image_config, ImageConfig},
+    logs::{copy_output, LogWriter},
+    mount::{
+        mount_bind_and_tmpfs_mounts, mount_bind_mounts, remount_root_read_only, resolve_in_root,
+        Mount, Tmpfs,
+    },
+    network::{delete_netns, fetch_ip_address, setup_netns, setup_veths},
+    restart::{next_restart_delay, RestartPolicy},
+    rootless::{finish_id_mappings, is_rootless, run_in_user_namespace, wait_for_id_mappings},
+    seccomp::{compile_profile, default_profile, install_filter, Profile},
+    tty::{
+        close_pty, forward_window_size, receive_fd, relay_pty, setup_container_tty, write_all,
+        RawTerminal,
+    },
+    user::{resolve_user, switch_user},
+    volume::populate_volume,
 };
 
+const DEFAULT_PATH_ENV: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+// The process started in the container: its argv, environment
+// ("KEY=VALUE"), working directory, user ("user[:group]"), capabilities and
+// seccomp profile.
+pub struct Process {
+    pub args: Vec<String>,
+    pub env: Vec<String>,
+    pub cwd: String,
+    pub user: String,
+    pub tty: bool,
+    pub interactive: bool,
+    pub capabilities: Vec<String>,
+    pub seccomp_profile: Option<Profile>,
+}
+
+impl Process {
+    // Falls back to the image's Entrypoint/Cmd, Env, WorkingDir and User
+    // the same way Docker does.
+    fn new(
+        command: Vec<String>,
+        image_config: &ImageConfig,
+        tty: bool,
+        interactive: bool,
+    ) -> Result<Self> {
+        let mut args = image_config.entrypoint.clone().unwrap_or_default();
+        if command.is_empty() {
+            args.extend(image_config.cmd.clone().unwrap_or_default());
+        } else {
+            args.extend(command);
+        }
+        if args.is_empty() {
+            return Err(anyhow!("No command specified"));
+        }
+
+        let mut env = image_config.env.clone().unwrap_or_default();
+        if !env.iter().any(|e| e.starts_with("PATH=")) {
+            env.push(format!("PATH={}", DEFAULT_PATH_ENV));
+        }
+        if tty && !env.iter().any(|e| e.starts_with("TERM=")) {
+            env.push("TERM=xterm".to_string());
+        }
+
+        let cwd = match &image_config.working_dir {
+            Some(dir) if !dir.is_empty() => dir.clone(),
+            _ => "/".to_string(),
+        };
+
+        Ok(Process {
+            args: args,
+            env: env,
+            cwd: cwd,
+            user: image_config.user.clone().unwrap_or_default(),
+            tty: tty,
+            interactive: interactive,
+            capabilities: default_capabilities(),
+            seccomp_profile: Some(default_profile()),
+        })
+    }
+
+    fn path_env(&self) -> &str {
+        self.env
+            .iter()
+            .find(|e| e.starts_with("PATH="))
+            .map(|e| &e["PATH=".len()..])
+            .unwrap_or(DEFAULT_PATH_ENV)
+    }
+}
+
 pub struct Container {
     pub id: String,
     pub image_name: String,
     pub image_hash: String,
-    pub command: String,
+    pub command: Vec<String>,
+    pub state: ContainerState,
+    pub exit_code: Option<i32>,
+    pub mounts: Vec<Mount>,
+}
+
+// created -> running (<-> paused) -> exited (-> restarting -> running)
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ContainerState {
+    Created,
+    Running,
+    Paused,
+    Restarting,
+    Exited,
+}
+
+impl ContainerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContainerState::Created => "created",
+            ContainerState::Running => "running",
+            ContainerState::Paused => "paused",
+            ContainerState::Restarting => "restarting",
+            ContainerState::Exited => "exited",
+        }
+    }
+}
+
+impl FromStr for ContainerState {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "created" => Ok(ContainerState::Created),
+            "running" => Ok(ContainerState::Running),
+            "paused" => Ok(ContainerState::Paused),
+            "restarting" => Ok(ContainerState::Restarting),
+            "exited" => Ok(ContainerState::Exited),
+            _ => Err(anyhow!("Unknown container state: {}", s)),
+        }
+    }
+}
+
+impl Container {
+    fn status(&self) -> String {
+        match self.state {
+            ContainerState::Created => "Created".to_string(),
+            ContainerState::Running => "Up".to_string(),
+            ContainerState::Paused => "Up (Paused)".to_string(),
+            ContainerState::Restarting => {
+                format!("Restarting ({})", self.exit_code.unwrap_or(-1))
+            }
+            ContainerState::Exited => format!("Exited ({})", self.exit_code.unwrap_or(-1)),
+        }
+    }
+}
+
+// Options of `rocker run`.
+pub struct RunOptions {
+    pub mem: Option<String>,
+    pub cpus: Option<f32>,
+    pub pids_limit: Option<i32>,
+    pub detach: bool,
+    pub auto_remove: bool,
+    pub env: Vec<String>,
+    pub tty: bool,
+    pub interactive: bool,
+    pub mounts: Vec<Mount>,
+    pub capabilities: Vec<String>,
+    pub seccomp_profile: Option<Profile>,
+    pub read_only: bool,
+    pub tmpfs: Vec<Tmpfs>,
+    pub user: Option<String>,
+    pub workdir: Option<String>,
+    pub hostname: Option<String>,
+    pub etc_files: EtcFilesOptions,
+    pub restart_policy: RestartPolicy,
 }
 
 pub async fn run_container(
-    mem: Option<String>,
-    cpus: Option<f32>,
-    pids: Option<i32>,
     image_name: String,
     registry_username: Option<String>,
     registry_password: Option<String>,
-    command: String,
-) -> Result<()> {
+    command: Vec<String>,
+    options: RunOptions,
+) -> Result<Option<i32>> {
+    if is_rootless()
+        && (options.mem.is_some() || options.cpus.is_some() || options.pids_limit.is_some())
+    {
+        return Err(anyhow!(
+            "Resource limits (--mem, --cpus and --pids-limit) are not supported in rootless mode"
+        ));
+    }
+
+    if let Some(workdir) = &options.workdir {
+        if !workdir.starts_with('/') {
+            return Err(anyhow!(
+                "The working directory must be absolute: {}",
+                workdir
+            ));
+        }
+    }
+    if options.auto_remove && options.restart_policy != RestartPolicy::No {
+        return Err(anyhow!("Conflicting options: --restart and --rm"));
+    }
+    if let Some(hostname) = &options.hostname {
+        if hostname.is_empty() || hostname.len() > 63 {
+            return Err(anyhow!("Invalid hostname: {}", hostname));
+        }
+    }
+
     let container_id = create_container_id()?;
-    let (image_hash, manifest) =
+    let image_hash =
         download_image_if_needed(&image_name, registry_username, registry_password).await?;
     create_container_directories(&container_id)?;
-    mount_overlay_fs(&manifest, &container_id, &image_hash)?;
-    setup_netns(&container_id).await?;
-    setup_veths(&container_id).await?;
-    // TODO: configure NAT to connect to internet
+    let overlay_options = overlay_mount_options(&container_id, &image_hash)?;
+    // A rootless container mounts its filesystems in its own user namespace
+    // and shares the host's network.
+    if !is_rootless() {
+        mount_overlay_fs(&container_id, &overlay_options)?;
+        populate_volumes(&container_id, &options.mounts)?;
+        setup_netns(&container_id).await?;
+        setup_veths(&container_id).await?;
+        // TODO: configure NAT to connect to internet
+    }
+
+    let mut process = Process::new(
+        command,
+        &load_image_config(&image_hash)?,
+        options.tty,
+        options.interactive,
+    )?;
+    merge_env(&mut process.env, &options.env);
+    process.capabilities = options.capabilities.clone();
+    process.seccomp_profile = options.seccomp_profile.clone();
+    if let Some(user) = &options.user {
+        process.user = user.clone();
+    }
+    if let Some(workdir) = &options.workdir {
+        process.cwd = workdir.clone();
+    }
 
-    let mnt_path = format!("{}/{}/fs/mnt", ROCKER_CONTAINERS_PATH, &container_id);
+    let db = open_db()?;
+    db.insert(
+        container_commands_key(&container_id),
+        serde_json::to_string(&process.args)?.as_str(),
+    )?;
+    db.insert(
+        container_envs_key(&container_id),
+        serde_json::to_string(&process.env)?.as_str(),
+    )?;
+    db.insert(
+        container_image_hashes_key(&container_id),
+        image_hash.as_str(),
+    )?;
+    db.insert(
+        container_created_at_key(&container_id),
+        Utc::now().to_rfc3339().as_str(),
+    )?;
+    db.insert(
+        container_mounts_key(&container_id),
+        serde_json::to_string(&options.mounts)?.as_str(),
+    )?;
+    db.insert(
+        container_capabilities_key(&container_id),
+        serde_json::to_string(&process.capabilities)?.as_str(),
+    )?;
+    db.insert(
+        container_seccomp_profiles_key(&container_id),
+        serde_json::to_string(&process.seccomp_profile)?.as_str(),
+    )?;
+    db.insert(container_users_key(&container_id), process.user.as_str())?;
+    db.insert(
+        container_working_dirs_key(&container_id),
+        process.cwd.as_str(),
+    )?;
+    db.insert(
+        container_restart_policies_key(&container_id),
+        options.restart_policy.to_string().as_str(),
+    )?;
+    if options.auto_remove {
+        db.insert(container_auto_removes_key(&container_id), "1")?;
+    }
+    drop(db);
+    set_container_state(&container_id, ContainerState::Created)?;
+
+    if options.detach {
+        run_detached_container(&container_id, &process, &options, &overlay_options)?;
+        return Ok(None);
+    }
+
+    let started = start_container(&container_id, &process, &options, &overlay_options)?;
+    let status = supervise_container(
+        &container_id,
+        &process,
+        &options,
+        &overlay_options,
+        started,
+        process.interactive,
+    )?;
+    println!("Container {} done", &container_id);
+
+    cleanup_container(&container_id).await?;
+    Ok(Some(exit_code_of(status)?))
+}
+
+fn populate_volumes(container_id: &str, mounts: &[Mount]) -> Result<()> {
+    let mnt_path = format!("{}/{}/fs/mnt", *ROCKER_CONTAINERS_PATH, container_id);
+    for m in mounts.iter().filter(|m| m.name.is_some()) {
+        populate_volume(&m.source, &mnt_path, &m.destination)?;
+    }
+    Ok(())
+}
+
+// Forks a supervisor (shim) process which outlives the rocker command. The shim
+// starts the container, waits for it (restarting it if its restart policy says
+// so), records its exit status and cleans up.
+fn run_detached_container(
+    container_id: &str,
+    process: &Process,
+    options: &RunOptions,
+    overlay_options: &str,
+) -> Result<()> {
+    let (read_fd, write_fd) = pipe()?;
+
+    fork_fn(
+        || {
+            close(read_fd).unwrap();
+            setsid().unwrap();
+            redirect_stdio_to_dev_null().unwrap();
+
+            let (pid, stdio) =
+                match start_container(container_id, process, options, overlay_options) {
+                    Ok(res) => res,
+                    Err(e) => {
+                        let report = format!("1{}\n", format!("{:#}", e).replace('\n', " "));
+                        let _ = write_all(write_fd, report.as_bytes());
+                        close(write_fd).unwrap();
+                        // Like Docker, a container which fails to start has
+                        // exited with 128.
+                        let _ = record_exit_code(container_id, 128);
+                        run_cleanup_in_new_runtime(container_id);
+                        return;
+                    }
+                };
+            write_all(write_fd, b"0\n").unwrap();
+            close(write_fd).unwrap();
+
+            // Cleans up even if a restart has failed.
+            let started = (pid, stdio);
+            let _ = supervise_container(
+                container_id,
+                process,
+                options,
+                overlay_options,
+                started,
+                false,
+            );
+            run_cleanup_in_new_runtime(container_id);
+        },
+        false,
+    );
+
+    // The shim reports "0" once the container has started, or "1" followed
+    // by the error, on a single line. The container itself may hold the write
+    // end as well, so EOF can't be waited for.
+    close(write_fd)?;
+    let mut report = Vec::new();
+    let mut buf = [0u8; 1];
+    while read(read_fd, &mut buf)? == 1 && buf[0] != b'\n' {
+        report.push(buf[0]);
+    }
+    close(read_fd)?;
+    match report.split_first() {
+        Some((b'0', _)) => {}
+        Some((_, error)) => {
+            return Err(anyhow!(
+                "Failed to start container {}: {}",
+                container_id,
+                String::from_utf8_lossy(error)
+            ))
+        }
+        None => return Err(anyhow!("Failed to start container: {}", container_id)),
+    }
+
+    println!("{}", container_id);
+    Ok(())
+}
+
+// `overlay_options` are only used in rootless mode, in which the overlay is
+// mounted in the container's user namespace.
+fn start_container(
+    container_id: &str,
+    process: &Process,
+    options: &RunOptions,
+    overlay_options: &str,
+) -> Result<(Pid, ProcessStdio)> {
+    let mnt_path = format!("{}/{}/fs/mnt", *ROCKER_CONTAINERS_PATH, container_id);
     const CONTAINER_STACK_SIZE: usize = 1024 * 1024;
     let mut stack = Box::new([0; CONTAINER_STACK_SIZE]);
+    let console_socket = create_console_socket(process)?;
+    let rootless = is_rootless();
+    let sync_pipe = if rootless { Some(pipe()?) } else { None };
+    // (read end, write end) of stdout and stderr, which are logged.
+    let output_pipes = if process.tty {
+        None
+    } else {
+        Some((pipe2(OFlag::O_CLOEXEC)?, pipe2(OFlag::O_CLOEXEC)?))
+    };
+    // (read end, write end) of stdin of a detached container run with `-i`,
+    // which is written to by `rocker attach`.
+    let stdin_pipe = if process.interactive && !process.tty && options.detach {
+        Some(pipe2(OFlag::O_CLOEXEC)?)
+    } else {
+        None
+    };
+    let hostname = options.hostname.as_deref().unwrap_or(container_id);
+    let ip_addr = fetch_ip_address(container_id)?;
+    let etc_mounts = create_etc_files(
+        container_id,
+        hostname,
+        ip_addr.as_deref(),
+        &options.etc_files,
+    )?;
+    let seccomp_filter = process
+        .seccomp_profile
+        .as_ref()
+        .map(|profile| compile_profile(profile, &process.capabilities))
+        .transpose()?;
 
     let cb = Box::new(|| {
-        let netns_path = format!("{}/{}", ROCKER_NETNS_PATH, &format!("ns-{}", &container_id));
-        setns_by_fd_path(&netns_path, CloneFlags::CLONE_NEWNET).unwrap();
+        if let Some(sync_pipe) = sync_pipe {
+            wait_for_id_mappings(sync_pipe).unwrap();
+        } else {
+            let netns_path = format!("{}/{}", ROCKER_NETNS_PATH, &format!("ns-{}", container_id));
+            setns_by_fd_path(&netns_path, CloneFlags::CLONE_NEWNET).unwrap();
+        }
+        make_mounts_private().unwrap();
 
-        nix::unistd::sethostname(&container_id).unwrap();
+        nix::unistd::sethostname(hostname).unwrap();
 
-        chroot(Path::new(&mnt_path)).unwrap();
-        chdir("/").unwrap();
+        if !process.tty && !process.interactive {
+            redirect_stdin_to_dev_null().unwrap();
+        }
+        if let Some(((_, stdout), (_, stderr))) = output_pipes {
+            dup2(stdout, libc::STDOUT_FILENO).unwrap();
+            dup2(stderr, libc::STDERR_FILENO).unwrap();
+        }
+        if let Some((stdin, _)) = stdin_pipe {
+            dup2(stdin, libc::STDIN_FILENO).unwrap();
+        }
 
-        mount_container_fs().unwrap();
+        if rootless {
+            mount_overlay_fs(container_id, overlay_options).unwrap();
+            populate_volumes(container_id, &options.mounts).unwrap();
+        }
+        let tmp_is_tmpfs = options.tmpfs.iter().any(|t| t.destination == "/tmp");
+        mount_container_fs(&mnt_path, !tmp_is_tmpfs).unwrap();
+        // Mounts given with -v come later so that they can replace these files.
+        mount_bind_mounts(&mnt_path, &etc_mounts).unwrap();
+        mount_bind_and_tmpfs_mounts(&mnt_path, &options.mounts, &options.tmpfs).unwrap();
+        // The working directory can't be created once the root is read-only.
+        if let Err(e) = create_working_dir(&mnt_path, &process.cwd) {
+            eprintln!("failed to create working directory {}: {}", &process.cwd, e);
+            return 126;
+        }
+        if options.read_only {
+            remount_root_read_only(&mnt_path).unwrap();
+        }
+        pivot_root_fs(&mnt_path).unwrap();
 
-        execv(
-            &CString::new((&command).to_string()).unwrap(),
-            &[CString::new((&command).to_string()).unwrap()],
-        )
-        .unwrap();
+        if let Some((_, container_side)) = console_socket {
+            setup_container_tty(container_side).unwrap();
+        }
 
-        return 0;
+        if let Err(e) = enter_working_dir(&process.cwd) {
+            eprintln!(
+                "failed to change working directory to {}: {}",
+                &process.cwd, e
+            );
+            return 126;
+        }
+        // The bounding set can only be changed before switching to a user
+        // without CAP_SETPCAP.
+        if let Err(e) = drop_bounding_capabilities(&process.capabilities) {
+            eprintln!("failed to set capabilities: {}", e);
+            return 126;
+        }
+        // Without no_new_privs, installing a filter needs CAP_SYS_ADMIN too.
+        if let Some(filter) = &seccomp_filter {
+            if let Err(e) = install_filter(filter) {
+                eprintln!("{}", e);
+                return 126;
+            }
+        }
+        if let Err(e) = resolve_user(&process.user).and_then(|user| switch_user(&user)) {
+            eprintln!("failed to switch user: {}", e);
+            return 126;
+        }
+        if let Err(e) = set_capabilities(&process.capabilities) {
+            eprintln!("failed to set capabilities: {}", e);
+            return 126;
+        }
+
+        exec_command(process)
     });
 
-    let clone_flags = CloneFlags::CLONE_NEWNS
+    let mut clone_flags = CloneFlags::CLONE_NEWNS
         | CloneFlags::CLONE_NEWPID
         | CloneFlags::CLONE_NEWUTS
         | CloneFlags::CLONE_NEWIPC;
+    if rootless {
+        clone_flags.insert(CloneFlags::CLONE_NEWUSER);
+    }
     let pid = clone(cb, &mut *stack, clone_flags, Some(Signal::SIGCHLD as i32))
         .with_context(|| "fialed to clone")?;
+    if let Some(sync_pipe) = sync_pipe {
+        finish_id_mappings(pid, sync_pipe)?;
+    }
 
-    let db = sled::open(ROCKER_DB_PATH).unwrap();
-    db.insert(container_commands_key(&container_id), command.as_str())?;
+    let db = open_db()?;
+    db.insert(container_pids_key(container_id), pid.to_string().as_str())?;
     db.insert(
-        container_image_hashes_key(&container_id),
-        image_hash.as_str(),
+        container_supervisor_pids_key(container_id),
+        getpid().to_string().as_str(),
+    )?;
+    db.insert(
+        container_started_at_key(container_id),
+        Utc::now().to_rfc3339().as_str(),
     )?;
-    db.insert(container_pids_key(&container_id), pid.to_string().as_str())?;
     drop(db);
+    set_container_state(container_id, ContainerState::Running)?;
 
-    create_cgroup(&container_id, pid.as_raw() as u32, mem, cpus, pids)?;
-    waitpid(pid, None)?;
-    println!("Container {} done", &container_id);
+    create_cgroup(
+        container_id,
+        pid.as_raw() as u32,
+        options.mem.clone(),
+        options.cpus,
+        options.pids_limit,
+    )?;
+
+    let stdin = match stdin_pipe {
+        Some((stdin_r, stdin_w)) => {
+            close(stdin_r)?;
+            Some(stdin_w)
+        }
+        None => None,
+    };
+    let stdio = match output_pipes {
+        Some(((stdout_r, stdout_w), (stderr_r, stderr_w))) => {
+            close(stdout_w)?;
+            close(stderr_w)?;
+            ProcessStdio::Pipes {
+                stdin: stdin,
+                stdout: stdout_r,
+                stderr: stderr_r,
+            }
+        }
+        None => match receive_pty_master(console_socket)? {
+            Some(master) => ProcessStdio::Tty(master),
+            None => ProcessStdio::Inherited,
+        },
+    };
+    Ok((pid, stdio))
+}
+
+// Waits for the container and restarts it according to its restart policy
+// until it exits for good, and returns its last wait status. The container is
+// restarted in the same filesystem and network namespace. Meanwhile, other
+// processes can attach to the container with `rocker attach`.
+fn supervise_container(
+    container_id: &str,
+    process: &Process,
+    options: &RunOptions,
+    overlay_options: &str,
+    started: (Pid, ProcessStdio),
+    interactive: bool,
+) -> Result<WaitStatus> {
+    let attach = AttachServer::listen(container_id, process.tty)?;
+    let result = restart_container_until_done(
+        container_id,
+        process,
+        options,
+        overlay_options,
+        started,
+        interactive,
+        &attach,
+    );
+    // Attached clients exit with the exit code of the container.
+    attach.close(
+        result
+            .as_ref()
+            .ok()
+            .and_then(|status| exit_code_of(*status).ok()),
+    );
+    result
+}
 
-    umount_container_fs(&mnt_path).unwrap();
+fn restart_container_until_done(
+    container_id: &str,
+    process: &Process,
+    options: &RunOptions,
+    overlay_options: &str,
+    started: (Pid, ProcessStdio),
+    interactive: bool,
+    attach: &Arc<AttachServer>,
+) -> Result<WaitStatus> {
+    let log = LogWriter::open(container_id)?;
+    let (mut pid, mut stdio) = started;
+    let mut restart_count = 0;
+    let mut restart_delay = None;
+    loop {
+        let started_at = Instant::now();
+        let status = wait_for_process(
+            pid,
+            stdio,
+            interactive,
+            Some(log.clone()),
+            Some(attach.clone()),
+        )?;
+        let exit_code = record_exit_status(container_id, status)?;
 
-    let db = sled::open(ROCKER_DB_PATH).unwrap();
+        if !options
+            .restart_policy
+            .should_restart(exit_code, restart_count)
+            || is_manually_stopped(container_id)?
+        {
+            return Ok(status);
+        }
+
+        let delay = next_restart_delay(restart_delay, started_at.elapsed());
+        restart_delay = Some(delay);
+        set_container_state(container_id, ContainerState::Restarting)?;
+        if !wait_for_restart(container_id, delay)? {
+            set_container_state(container_id, ContainerState::Exited)?;
+            return Ok(status);
+        }
+
+        restart_count += 1;
+        let db = open_db()?;
+        db.insert(
+            container_restart_counts_key(container_id),
+            restart_count.to_string().as_str(),
+        )?;
+        drop(db);
+
+        match start_container(container_id, process, options, overlay_options) {
+            Ok(res) => {
+                pid = res.0;
+                stdio = res.1;
+            }
+            Err(e) => {
+                set_container_state(container_id, ContainerState::Exited)?;
+                return Err(e);
+            }
+        }
+    }
+}
+
+// Sleeps for `delay`, and returns false if the container is stopped meanwhile.
+fn wait_for_restart(container_id: &str, delay: Duration) -> Result<bool> {
+    let deadline = Instant::now() + delay;
+    while Instant::now() < deadline {
+        if is_manually_stopped(container_id)? {
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(100).min(delay));
+    }
+    Ok(!is_manually_stopped(container_id)?)
+}
+
+fn is_manually_stopped(container_id: &str) -> Result<bool> {
+    let db = open_db()?;
+    Ok(db.get(container_manual_stops_key(container_id))?.is_some())
+}
+
+// Keeps the supervisor from restarting the container.
+fn mark_manually_stopped(container_id: &str) -> Result<()> {
+    let db = open_db()?;
+    db.insert(container_manual_stops_key(container_id), "1")?;
+    Ok(())
+}
+
+// A socket pair over which the container sends the master side of its pty
+// when a tty is requested. (supervisor side, container side)
+fn create_console_socket(process: &Process) -> Result<Option<(RawFd, RawFd)>> {
+    if !process.tty {
+        return Ok(None);
+    }
+    let sockets = socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::SOCK_CLOEXEC,
+    )?;
+    Ok(Some(sockets))
+}
+
+// Must be called after the container has been cloned.
+fn receive_pty_master(console_socket: Option<(RawFd, RawFd)>) -> Result<Option<RawFd>> {
+    match console_socket {
+        Some((supervisor_side, container_side)) => {
+            close(container_side)?;
+            let master = receive_fd(supervisor_side)?;
+            close(supervisor_side)?;
+            Ok(Some(master))
+        }
+        None => Ok(None),
+    }
+}
+
+// 128 + the signal number if the process was killed by a signal, like a shell.
+fn exit_code_of(status: WaitStatus) -> Result<i32> {
+    match status {
+        WaitStatus::Exited(_, code) => Ok(code),
+        WaitStatus::Signaled(_, signal, _) => Ok(128 + signal as i32),
+        _ => Err(anyhow!("Unexpected wait status: {:?}", status)),
+    }
+}
+
+fn record_exit_status(container_id: &str, status: WaitStatus) -> Result<i32> {
+    let exit_code = exit_code_of(status)?;
+    record_exit_code(container_id, exit_code)?;
+    Ok(exit_code)
+}
+
+fn record_exit_code(container_id: &str, exit_code: i32) -> Result<()> {
+    let db = open_db()?;
+    db.insert(
+        container_exit_codes_key(container_id),
+        exit_code.to_string().as_str(),
+    )?;
+    db.insert(
+        container_finished_at_key(container_id),
+        Utc::now().to_rfc3339().as_str(),
+    )?;
+    drop(db);
+    set_container_state(container_id, ContainerState::Exited)
+}
+
+fn set_container_state(container_id: &str, state: ContainerState) -> Result<()> {
+    let db = open_db()?;
+    db.insert(container_states_key(container_id), state.as_str())?;
+    Ok(())
+}
+
+pub fn fetch_container_state(container_id: &str) -> Result<Option<ContainerState>> {
+    let db = open_db()?;
+    match db.get(container_states_key(container_id))? {
+        Some(state) => Ok(Some(String::from_utf8(state.to_vec())?.parse()?)),
+        None => Ok(None),
+    }
+}
+
+// Releases what the container only needs while it runs. Its records and
+// directory are kept until `rocker rm` unless it was started with `--rm`.
+async fn cleanup_container(container_id: &str) -> Result<()> {
+    release_container_resources(container_id).await?;
+
+    let db = open_db()?;
+    let auto_remove = db.get(container_auto_removes_key(container_id))?.is_some();
+    drop(db);
+    if auto_remove {
+        remove_container_records(container_id)?;
+    }
+    Ok(())
+}
+
+async fn release_container_resources(container_id: &str) -> Result<()> {
+    // A rootless container has nothing mounted in the host's mount namespace
+    // and no network of its own.
+    if !is_rootless() {
+        release_overlay_and_network(container_id).await?;
+    }
+
+    let db = open_db()?;
+    db.remove(container_pids_key(container_id))?;
+    db.remove(container_supervisor_pids_key(container_id))?;
+    Ok(())
+}
+
+// The other mounts of the container are private to its mount namespace and
+// are gone along with it.
+async fn release_overlay_and_network(container_id: &str) -> Result<()> {
+    let db = open_db()?;
 
     let res = db.remove(veth_ip_addresses_key(&format!(
         "ns-veth-{}",
@@ -113,13 +840,190 @@ pub async fn run_container(
     let ip_addr = String::from_utf8(res.unwrap().to_vec()).unwrap();
 
     db.remove(used_ip_addresses_key(&ip_addr))?;
-    db.remove(container_commands_key(&container_id))?;
-    db.remove(container_image_hashes_key(&container_id))?;
-    db.remove(container_pids_key(&container_id))?;
+    drop(db);
+
+    delete_netns(container_id).await?;
+    umount_overlay_fs(container_id)?;
+    Ok(())
+}
+
+fn remove_container_records(container_id: &str) -> Result<()> {
+    let db = open_db()?;
+    db.remove(container_commands_key(container_id))?;
+    db.remove(container_envs_key(container_id))?;
+    db.remove(container_image_hashes_key(container_id))?;
+    db.remove(container_states_key(container_id))?;
+    db.remove(container_exit_codes_key(container_id))?;
+    db.remove(container_created_at_key(container_id))?;
+    db.remove(container_started_at_key(container_id))?;
+    db.remove(container_finished_at_key(container_id))?;
+    db.remove(container_auto_removes_key(container_id))?;
+    db.remove(container_mounts_key(container_id))?;
+    db.remove(container_capabilities_key(container_id))?;
+    db.remove(container_seccomp_profiles_key(container_id))?;
+    db.remove(container_users_key(container_id))?;
+    db.remove(container_working_dirs_key(container_id))?;
+    db.remove(container_restart_policies_key(container_id))?;
+    db.remove(container_restart_counts_key(container_id))?;
+    db.remove(container_manual_stops_key(container_id))?;
+    drop(db);
 
-    delete_netns(&container_id).await?;
-    umount_overlay_fs(&container_id)?;
-    fs::remove_dir_all(format!("{}/{}", ROCKER_CONTAINERS_PATH, &container_id))?;
+    let container_path = format!("{}/{}", *ROCKER_CONTAINERS_PATH, container_id);
+    if is_rootless() {
+        run_in_user_namespace(|| Ok(fs::remove_dir_all(&container_path)?))?;
+    } else {
+        fs::remove_dir_all(&container_path)?;
+    }
+    Ok(())
+}
+
+pub async fn remove_container(container_id: &str, force: bool) -> Result<()> {
+    let state = match fetch_container_state(container_id)? {
+        Some(state) => state,
+        None => {
+            println!("container not found: {}", &container_id);
+            return Ok(());
+        }
+    };
+
+    match state {
+        ContainerState::Running | ContainerState::Paused | ContainerState::Restarting => {
+            if !force {
+                return Err(anyhow!(
+                    "You cannot remove a running container {}. Stop the container before attempting removal or use -f",
+                    container_id
+                ));
+            }
+            // The supervisor removes everything once the container is gone.
+            let db = open_db()?;
+            db.insert(container_auto_removes_key(container_id), "1")?;
+            drop(db);
+            kill_container(container_id, "KILL").await?;
+            if let Some(pid) = fetch_pid(&container_pids_key(container_id))? {
+                wait_for_container_exit(container_id, pid, Duration::from_secs(10)).await?;
+            }
+            return Ok(());
+        }
+        // The container never started, so nothing has been released yet.
+        ContainerState::Created => {
+            let _ = release_container_resources(container_id).await;
+        }
+        ContainerState::Exited => {}
+    }
+
+    remove_container_records(container_id)?;
+    println!("{}", container_id);
+    Ok(())
+}
+
+// The shim is forked from inside the tokio runtime, so it needs its own
+// runtime on a fresh thread to run the async cleanup.
+fn run_cleanup_in_new_runtime(container_id: &str) {
+    let c_id = container_id.to_string();
+    thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(cleanup_container(&c_id)).unwrap();
+    })
+    .join()
+    .expect("Thread paniced");
+}
+
+// The supervisor's side of the stdio of a process in the container.
+enum ProcessStdio {
+    Inherited,
+    Tty(RawFd),
+    Pipes {
+        stdin: Option<RawFd>,
+        stdout: RawFd,
+        stderr: RawFd,
+    },
+}
+
+// Relays the output of the process to the caller (and the log and the
+// attached clients) and, with a tty, the caller's terminal to the process
+// until it exits. Input from the attached clients goes to the process too.
+fn wait_for_process(
+    pid: Pid,
+    stdio: ProcessStdio,
+    interactive: bool,
+    log: Option<Arc<LogWriter>>,
+    attach: Option<Arc<AttachServer>>,
+) -> Result<WaitStatus> {
+    let mut raw_terminal = None;
+    let mut input = None;
+    let outputs = match stdio {
+        ProcessStdio::Inherited => Vec::new(),
+        ProcessStdio::Tty(master) => {
+            if interactive {
+                raw_terminal = RawTerminal::new(libc::STDIN_FILENO)?;
+            }
+            forward_window_size(master)?;
+            input = Some(master);
+            vec![relay_pty(master, interactive, log, attach.clone())]
+        }
+        ProcessStdio::Pipes {
+            stdin,
+            stdout,
+            stderr,
+        } => {
+            input = stdin;
+            vec![
+                copy_output(
+                    stdout,
+                    libc::STDOUT_FILENO,
+                    "stdout",
+                    log.clone(),
+                    attach.clone(),
+                ),
+                copy_output(stderr, libc::STDERR_FILENO, "stderr", log, attach.clone()),
+            ]
+        }
+    };
+    if let Some(attach) = &attach {
+        attach.set_input(input);
+    }
+
+    let status = waitpid(pid, None)?;
+    for output in outputs {
+        output.join().expect("Thread paniced");
+    }
+    drop(raw_terminal);
+    if let Some(attach) = &attach {
+        attach.set_input(None);
+    }
+    // A restarted container gets new ones.
+    match stdio {
+        ProcessStdio::Inherited => {}
+        ProcessStdio::Tty(master) => close_pty(master)?,
+        ProcessStdio::Pipes {
+            stdin,
+            stdout,
+            stderr,
+        } => {
+            if let Some(stdin) = stdin {
+                close(stdin)?;
+            }
+            close(stdout)?;
+            close(stderr)?;
+        }
+    }
+    Ok(status)
+}
+
+fn redirect_stdin_to_dev_null() -> Result<()> {
+    let dev_null = OpenOptions::new().read(true).open("/dev/null")?;
+    dup2(dev_null.as_raw_fd(), libc::STDIN_FILENO)?;
+    Ok(())
+}
+
+fn redirect_stdio_to_dev_null() -> Result<()> {
+    let dev_null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    for fd in 0..=2 {
+        dup2(dev_null.as_raw_fd(), fd)?;
+    }
     Ok(())
 }
 
@@ -127,7 +1031,7 @@ fn create_container_id() -> Result<String> {
     let mut random_bytes = rand::thread_rng().gen::<[u8; 6]>();
     let mut container_id = encode(random_bytes);
     let mut is_ok = false;
-    let db = sled::open(ROCKER_DB_PATH).unwrap();
+    let db = open_db()?;
 
     while !is_ok {
         match db.get(container_image_hashes_key(&container_id))? {
@@ -146,7 +1050,7 @@ fn create_container_id() -> Result<String> {
 }
 
 fn create_container_directories(container_id: &String) -> Result<()> {
-    let container_path = format!("{}{}{}", ROCKER_CONTAINERS_PATH, "/", container_id);
+    let container_path = format!("{}{}{}", *ROCKER_CONTAINERS_PATH, "/", container_id);
     let container_directories = [
         format!("{}{}", container_path, "/fs"),
         format!("{}{}", container_path, "/fs/mnt"),
@@ -161,33 +1065,27 @@ fn create_container_directories(container_id: &String) -> Result<()> {
     Ok(())
 }
 
-fn mount_overlay_fs(
-    manifest: &ManifestSchema2,
-    container_id: &String,
-    image_hash: &String,
-) -> Result<()> {
-    let image_base_path = format!("{}{}{}", ROCKER_IMAGES_PATH, "/", image_hash);
-    let mut src_layers: Vec<String> = Vec::new();
-    for layer in manifest.get_layers() {
-        src_layers.push(format!(
-            "{}{}{}{}",
-            image_base_path,
-            "/",
-            layer[7..=18].to_string(),
-            "/fs",
-        ));
-    }
+fn overlay_mount_options(container_id: &str, image_hash: &str) -> Result<String> {
+    let src_layers = fetch_image_layers(image_hash)?;
 
-    let container_fs_base_path = &format!("{}/{}/fs", ROCKER_CONTAINERS_PATH, container_id);
+    let container_fs_base_path = &format!("{}/{}/fs", *ROCKER_CONTAINERS_PATH, container_id);
     let src_layers_str = src_layers.join(":");
-    let options: &str = &format!(
+    let mut options = format!(
         "lowerdir={},upperdir={}/upperdir,workdir={}/workdir",
         src_layers_str, container_fs_base_path, container_fs_base_path
     );
+    // trusted.* xattrs can't be set in a user namespace.
+    if is_rootless() {
+        options.push_str(",userxattr");
+    }
+    Ok(options)
+}
 
+fn mount_overlay_fs(container_id: &str, options: &str) -> Result<()> {
+    let mounted_path = format!("{}/{}/fs/mnt", *ROCKER_CONTAINERS_PATH, container_id);
     nix::mount::mount::<Path, Path, [u8], str>(
         None,
-        Path::new(&format!("{}/mnt", container_fs_base_path)),
+        Path::new(&mounted_path),
         Some(b"overlay".as_ref()),
         MsFlags::empty(),
         Some(options),
@@ -196,91 +1094,196 @@ fn mount_overlay_fs(
     Ok(())
 }
 
-fn umount_overlay_fs(container_id: &String) -> Result<()> {
-    let mounted_path = format!("{}/{}/fs/mnt", ROCKER_CONTAINERS_PATH, container_id);
+// The overlay options of an existing container, from the image it was created
+// from.
+pub fn container_overlay_options(container_id: &str) -> Result<String> {
+    let db = open_db()?;
+    let image_hash = match db.get(container_image_hashes_key(container_id))? {
+        Some(image_hash) => String::from_utf8(image_hash.to_vec())?,
+        None => return Err(anyhow!("container not found: {}", container_id)),
+    };
+    drop(db);
+    overlay_mount_options(container_id, &image_hash)
+}
+
+// Mounts the overlay of a stopped container in a new mount namespace of the
+// calling thread, so that it's gone once the process exits.
+pub fn mount_overlay_fs_privately(container_id: &str, options: &str) -> Result<()> {
+    unshare(CloneFlags::CLONE_NEWNS)?;
+    make_mounts_private()?;
+    mount_overlay_fs(container_id, options)
+        .with_context(|| format!("Failed to mount the filesystem of {}", container_id))
+}
+
+fn umount_overlay_fs(container_id: &str) -> Result<()> {
+    let mounted_path = format!("{}/{}/fs/mnt", *ROCKER_CONTAINERS_PATH, container_id);
     nix::mount::umount(Path::new(&mounted_path))?;
     Ok(())
 }
 
-fn mount_container_fs() -> Result<()> {
-    create_dir_all("/proc")?;
-    nix::mount::mount::<str, Path, [u8], str>(
+// Must be called in the container's mount namespace before pivot_root.
+// `mount_tmp` is false when /tmp is given as a `--tmpfs` instead.
+fn mount_container_fs(rootfs: &str, mount_tmp: bool) -> Result<()> {
+    create_dir_all(format!("{}/proc", rootfs))?;
+    nix::mount::mount::<str, str, [u8], str>(
         Some("proc"),
-        Path::new("/proc"),
+        &format!("{}/proc", rootfs),
         Some(b"proc".as_ref()),
         MsFlags::empty(),
         Some(""),
     )
     .unwrap();
 
-    nix::mount::mount::<str, Path, [u8], str>(
-        Some("tmpfs"),
-        Path::new("/tmp"),
-        Some(b"tmpfs".as_ref()),
-        MsFlags::empty(),
-        Some(""),
-    )
-    .unwrap();
+    if mount_tmp {
+        nix::mount::mount::<str, str, [u8], str>(
+            Some("tmpfs"),
+            &format!("{}/tmp", rootfs),
+            Some(b"tmpfs".as_ref()),
+            MsFlags::empty(),
+            Some(""),
+        )
+        .unwrap();
+    }
 
-    nix::mount::mount::<str, Path, [u8], str>(
+    nix::mount::mount::<str, str, [u8], str>(
         Some("tmpfs"),
-        Path::new("/dev"),
+        &format!("{}/dev", rootfs),
         Some(b"tmpfs".as_ref()),
         MsFlags::empty(),
         Some(""),
     )
     .unwrap();
 
-    create_dir_all("/dev/pts")?;
-    nix::mount::mount::<str, Path, [u8], str>(
+    create_dir_all(format!("{}/dev/pts", rootfs))?;
+    nix::mount::mount::<str, str, [u8], str>(
         Some("devpts"),
-        Path::new("/dev/pts"),
+        &format!("{}/dev/pts", rootfs),
         Some(b"devpts".as_ref()),
         MsFlags::empty(),
-        Some(""),
+        Some("newinstance,ptmxmode=0666,mode=0620"),
     )
     .unwrap();
+    // Let openpty() allocate ptys from the container's own devpts.
+    symlink("pts/ptmx", format!("{}/dev/ptmx", rootfs))?;
+    create_device_nodes(rootfs)?;
 
-    create_dir_all("/sys")?;
-    nix::mount::mount::<str, Path, [u8], str>(
-        Some("sysfs"),
-        Path::new("/sys"),
-        Some(b"sysfs".as_ref()),
-        MsFlags::empty(),
-        Some(""),
-    )
-    .unwrap();
+    create_dir_all(format!("{}/sys", rootfs))?;
+    if is_rootless() {
+        // sysfs can only be mounted by the owner of the network namespace,
+        // which is the host's one for a rootless container.
+        nix::mount::mount::<str, str, str, str>(
+            Some("/sys"),
+            &format!("{}/sys", rootfs),
+            None,
+            MsFlags::MS_BIND | MsFlags::MS_REC,
+            None,
+        )
+        .unwrap();
+    } else {
+        nix::mount::mount::<str, str, [u8], str>(
+            Some("sysfs"),
+            &format!("{}/sys", rootfs),
+            Some(b"sysfs".as_ref()),
+            MsFlags::empty(),
+            Some(""),
+        )
+        .unwrap();
+    }
+
+    Ok(())
+}
+
+// Keeps the mounts of the container from propagating to the host and the
+// other way around.
+fn make_mounts_private() -> Result<()> {
+    nix::mount::mount::<str, str, str, str>(
+        None,
+        "/",
+        None,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None,
+    )?;
+    Ok(())
+}
 
+// Makes `rootfs` (which is a mount point) the root of the mount namespace and
+// detaches the old root, so that the host's filesystem can't be reached from
+// the container anymore.
+fn pivot_root_fs(rootfs: &str) -> Result<()> {
+    chdir(rootfs)?;
+    // The old root is stacked on top of the new root, and unmounting "."
+    // detaches it without needing a directory to put it in.
+    pivot_root(".", ".")?;
+    umount2(".", MntFlags::MNT_DETACH)?;
+    chdir("/")?;
     Ok(())
 }
 
-fn umount_container_fs(container_mount_path: &str) -> Result<()> {
-    umount(Path::new(&format!("{}/dev/pts", &container_mount_path))).unwrap();
-    umount(Path::new(&format!("{}/dev", &container_mount_path))).unwrap();
-    umount(Path::new(&format!("{}/sys", &container_mount_path))).unwrap();
-    umount(Path::new(&format!("{}/proc", &container_mount_path))).unwrap();
-    umount(Path::new(&format!("{}/tmp", &container_mount_path))).unwrap();
+fn create_device_nodes(rootfs: &str) -> Result<()> {
+    let devices = [
+        ("/dev/null", 1, 3),
+        ("/dev/zero", 1, 5),
+        ("/dev/full", 1, 7),
+        ("/dev/random", 1, 8),
+        ("/dev/urandom", 1, 9),
+        ("/dev/tty", 5, 0),
+    ];
+    for (path, major, minor) in devices.iter() {
+        let target = format!("{}{}", rootfs, path);
+        // mknod() isn't allowed in a user namespace, so the host's devices
+        // are bind mounted instead.
+        if is_rootless() {
+            OpenOptions::new().create(true).write(true).open(&target)?;
+            nix::mount::mount::<str, str, str, str>(
+                Some(*path),
+                &target,
+                None,
+                MsFlags::MS_BIND,
+                None,
+            )?;
+            continue;
+        }
+
+        mknod(
+            target.as_str(),
+            SFlag::S_IFCHR,
+            Mode::from_bits_truncate(0o666),
+            makedev(*major, *minor),
+        )?;
+        // mknod() is subject to the umask.
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o666))?;
+    }
     Ok(())
 }
 
-pub fn print_running_containers() -> Result<()> {
-    println!("CONTAINER ID\tIMAGE\t\tCOMMAND");
+pub fn print_containers(all: bool) -> Result<()> {
+    println!("CONTAINER ID\tIMAGE\t\tCOMMAND\tSTATUS");
 
-    for container in fetch_running_containers()? {
+    for container in fetch_containers()? {
+        if !all
+            && container.state != ContainerState::Running
+            && container.state != ContainerState::Paused
+            && container.state != ContainerState::Restarting
+        {
+            continue;
+        }
         println!(
-            "{}\t{}\t{}",
-            container.id, container.image_name, container.command
+            "{}\t{}\t{}\t{}",
+            container.id,
+            container.image_name,
+            container.command.join(" "),
+            container.status()
         );
     }
 
     Ok(())
 }
 
-pub fn fetch_running_containers() -> Result<Vec<Container>> {
+pub fn fetch_containers() -> Result<Vec<Container>> {
     let mut containers = Vec::new();
 
-    let db = sled::open(ROCKER_DB_PATH)?;
-    for entry in fs::read_dir(ROCKER_CONTAINERS_PATH)? {
+    let db = open_db()?;
+    for entry in fs::read_dir(&*ROCKER_CONTAINERS_PATH)? {
         let path = entry?.path();
         let container_id = path.file_name().unwrap().to_string_lossy().to_string();
 
@@ -288,7 +1291,7 @@ pub fn fetch_running_containers() -> Result<Vec<Container>> {
             .get(container_commands_key(&container_id))
             .unwrap()
             .unwrap();
-        let command = String::from_utf8(command_res.to_vec()).unwrap();
+        let command: Vec<String> = serde_json::from_slice(&command_res)?;
 
         let image_hash_res = db
             .get(container_image_hashes_key(&container_id))
@@ -300,31 +1303,131 @@ pub fn fetch_running_containers() -> Result<Vec<Container>> {
         let image_name_and_tag = String::from_utf8(image_name_and_tag_res.to_vec()).unwrap();
         let image_name_and_tag: Vec<&str> = image_name_and_tag.split(":").collect();
 
+        let state = match db.get(container_states_key(&container_id))? {
+            Some(state) => String::from_utf8(state.to_vec())?.parse()?,
+            None => ContainerState::Created,
+        };
+        let exit_code = match db.get(container_exit_codes_key(&container_id))? {
+            Some(code) => Some(String::from_utf8(code.to_vec())?.parse()?),
+            None => None,
+        };
+
+        let mounts = match db.get(container_mounts_key(&container_id))? {
+            Some(mounts) => serde_json::from_slice(&mounts)?,
+            None => Vec::new(),
+        };
+
         containers.push(Container {
             id: container_id,
             image_hash: image_hash,
             image_name: image_name_and_tag[0].to_string(),
             command: command,
+            state: state,
+            exit_code: exit_code,
+            mounts: mounts,
         })
     }
 
     Ok(containers)
 }
 
-pub fn exec_command_in_container(container_id: &str, command: &str) -> Result<()> {
-    let db = sled::open(ROCKER_DB_PATH)?;
+// Options of `rocker exec`.
+pub struct ExecOptions {
+    pub env: Vec<String>,
+    pub tty: bool,
+    pub interactive: bool,
+    pub cap_add: Vec<String>,
+    pub cap_drop: Vec<String>,
+    pub privileged: bool,
+    pub user: Option<String>,
+    pub workdir: Option<String>,
+}
+
+pub fn exec_command_in_container(
+    container_id: &str,
+    command: &[String],
+    options: ExecOptions,
+) -> Result<()> {
+    let db = open_db()?;
     let container_pid_res = db.get(container_pids_key(&container_id))?;
+    let container_env: Vec<String> = match db.get(container_envs_key(&container_id))? {
+        Some(container_env) => serde_json::from_slice(&container_env)?,
+        None => Vec::new(),
+    };
+    let container_capabilities: Vec<String> =
+        match db.get(container_capabilities_key(&container_id))? {
+            Some(caps) => serde_json::from_slice(&caps)?,
+            None => default_capabilities(),
+        };
+    let seccomp_profile: Option<Profile> =
+        match db.get(container_seccomp_profiles_key(&container_id))? {
+            Some(profile) => serde_json::from_slice(&profile)?,
+            None => Some(default_profile()),
+        };
+    // Like Docker, exec runs as the container's user unless `--user` is given.
+    let container_user = match db.get(container_users_key(&container_id))? {
+        Some(user) => String::from_utf8(user.to_vec())?,
+        None => String::new(),
+    };
+    // The same goes for the working directory and `--workdir`.
+    let container_cwd = match db.get(container_working_dirs_key(&container_id))? {
+        Some(cwd) => String::from_utf8(cwd.to_vec())?,
+        None => "/".to_string(),
+    };
     drop(db);
 
-    if container_pid_res.is_none() {
-        println!("container not found: {}", &container_id);
+    if let Some(workdir) = &options.workdir {
+        if !workdir.starts_with('/') {
+            return Err(anyhow!(
+                "The working directory must be absolute: {}",
+                workdir
+            ));
+        }
+    }
+
+    let state = fetch_container_state(container_id)?;
+    if state == Some(ContainerState::Paused) {
+        println!("container is paused, unpause it first: {}", &container_id);
+        return Ok(());
+    }
+    if container_pid_res.is_none() || state == Some(ContainerState::Restarting) {
+        match state {
+            Some(_) => println!("container is not running: {}", &container_id),
+            None => println!("container not found: {}", &container_id),
+        }
         return Ok(());
     }
 
     let container_pid: u32 = String::from_utf8(container_pid_res.unwrap().to_vec())?.parse()?;
 
-    let mnt_path = format!("{}/{}/fs/mnt", ROCKER_CONTAINERS_PATH, &container_id);
+    let mut process = Process {
+        args: command.to_vec(),
+        env: container_env,
+        cwd: options.workdir.clone().unwrap_or(container_cwd),
+        user: options.user.clone().unwrap_or(container_user),
+        tty: options.tty,
+        interactive: options.interactive,
+        capabilities: resolve_capabilities(
+            &container_capabilities,
+            &options.cap_add,
+            &options.cap_drop,
+            options.privileged,
+        )?,
+        seccomp_profile: seccomp_profile,
+    };
+    merge_env(&mut process.env, &options.env);
+    if process.tty && !process.env.iter().any(|e| e.starts_with("TERM=")) {
+        process.env.push("TERM=xterm".to_string());
+    }
+
     const CONTAINER_STACK_SIZE: usize = 1024 * 1024;
+    let console_socket = create_console_socket(&process)?;
+    let rootless = is_rootless();
+    let seccomp_filter = process
+        .seccomp_profile
+        .as_ref()
+        .map(|profile| compile_profile(profile, &process.capabilities))
+        .transpose()?;
 
     let cb = Box::new(|| {
         let ns_base_path = format!("/proc/{}/ns", &container_pid);
@@ -332,24 +1435,60 @@ pub fn exec_command_in_container(container_id: &str, command: &str) -> Result<()
         let mntns_path = format!("{}/mnt", &ns_base_path);
         let pidns_path = format!("{}/pid", &ns_base_path);
         let utsns_path = format!("{}/uts", &ns_base_path);
-        let netns_path = format!("{}/{}", ROCKER_NETNS_PATH, &format!("ns-{}", &container_id));
+        // The other namespaces are owned by the user namespace of a rootless
+        // container, so it has to be joined first.
+        if rootless {
+            let userns_path = format!("{}/user", &ns_base_path);
+            setns_by_fd_path(&userns_path, CloneFlags::CLONE_NEWUSER).unwrap();
+        }
         setns_by_fd_path(&ipcns_path, CloneFlags::CLONE_NEWIPC).unwrap();
+        // Joining the mount namespace also moves the root directory to the
+        // container's root.
         setns_by_fd_path(&mntns_path, CloneFlags::CLONE_NEWNS).unwrap();
         setns_by_fd_path(&pidns_path, CloneFlags::CLONE_NEWPID).unwrap();
         setns_by_fd_path(&utsns_path, CloneFlags::CLONE_NEWUTS).unwrap();
-        setns_by_fd_path(&netns_path, CloneFlags::CLONE_NEWNET).unwrap();
+        if !rootless {
+            let netns_path = format!("{}/{}", ROCKER_NETNS_PATH, &format!("ns-{}", &container_id));
+            setns_by_fd_path(&netns_path, CloneFlags::CLONE_NEWNET).unwrap();
+        }
 
         let execv_cb = Box::new(|| {
-            nix::unistd::sethostname(&container_id).unwrap();
-            chroot(Path::new(&mnt_path)).unwrap();
-            chdir("/").unwrap();
-
-            execv(
-                &CString::new((&command).to_string()).unwrap(),
-                &[CString::new((&command).to_string()).unwrap()],
-            )
-            .unwrap();
-            return 0;
+            if !process.tty && !process.interactive {
+                redirect_stdin_to_dev_null().unwrap();
+            }
+
+            if let Some((_, container_side)) = console_socket {
+                setup_container_tty(container_side).unwrap();
+            }
+
+            if let Err(e) = enter_working_dir(&process.cwd) {
+                eprintln!(
+                    "failed to change working directory to {}: {}",
+                    &process.cwd, e
+                );
+                return 126;
+            }
+
+            if let Err(e) = drop_bounding_capabilities(&process.capabilities) {
+                eprintln!("failed to set capabilities: {}", e);
+                return 126;
+            }
+            if let Some(filter) = &seccomp_filter {
+                if let Err(e) = install_filter(filter) {
+                    eprintln!("{}", e);
+                    return 126;
+                }
+            }
+            if let Err(e) = resolve_user(&process.user).and_then(|user| switch_user(&user)) {
+                eprintln!("failed to switch user: {}", e);
+                return 126;
+            }
+            if let Err(e) = set_capabilities(&process.capabilities) {
+                eprintln!("failed to set capabilities: {}", e);
+                return 126;
+            }
+
+            exec_command(&process)
         });
 
         let ref mut execv_stack: [u8; CONTAINER_STACK_SIZE] = [0; CONTAINER_STACK_SIZE];
@@ -362,7 +1501,11 @@ pub fn exec_command_in_container(container_id: &str, command: &str) -> Result<()
         .with_context(|| "fialed to clone")
         .unwrap();
 
-        add_process_to_cgroup(container_id, execv_pid.as_raw() as u32).unwrap();
+        // An unprivileged user can't move processes out of the login session's
+        // cgroup.
+        if !rootless {
+            add_process_to_cgroup(container_id, execv_pid.as_raw() as u32).unwrap();
+        }
         waitpid(execv_pid, None).unwrap();
 
         return 0;
@@ -372,11 +1515,317 @@ pub fn exec_command_in_container(container_id: &str, command: &str) -> Result<()
     let pid = clone(cb, stack, CloneFlags::empty(), Some(Signal::SIGCHLD as i32))
         .with_context(|| "fialed to clone")?;
 
-    waitpid(pid, None)?;
+    let stdio = match receive_pty_master(console_socket)? {
+        Some(master) => ProcessStdio::Tty(master),
+        None => ProcessStdio::Inherited,
+    };
+    wait_for_process(pid, stdio, process.interactive, None, None)?;
 
     Ok(())
 }
 
+pub async fn stop_container(container_id: &str, timeout: u64) -> Result<()> {
+    let pid = match fetch_pid(&container_pids_key(container_id))? {
+        Some(pid) => pid,
+        None => {
+            println!("container not found: {}", &container_id);
+            return Ok(());
+        }
+    };
+
+    mark_manually_stopped(container_id)?;
+    // The process of a container waiting to be restarted is already gone, and
+    // its pid may have been reused.
+    let restarting = fetch_container_state(container_id)? == Some(ContainerState::Restarting);
+    if !restarting {
+        send_signal(pid, Signal::SIGTERM)?;
+        thaw_if_paused(container_id)?;
+    }
+    if !wait_for_container_exit(container_id, pid, Duration::from_secs(timeout)).await? {
+        println!(
+            "Container {} did not exit within {} seconds, sending SIGKILL",
+            container_id, timeout
+        );
+        if kill_cgroup(container_id, Signal::SIGKILL as i32).is_err() && !restarting {
+            send_signal(pid, Signal::SIGKILL)?;
+        }
+        wait_for_container_exit(container_id, pid, Duration::from_secs(10)).await?;
+    }
+
+    println!("{}", container_id);
+    Ok(())
+}
+
+// Blocks until each of the containers exits, and prints their exit codes in
+// order. A container which is already stopped returns at once.
+pub fn wait_containers(container_ids: &[String]) -> Result<()> {
+    for container_id in container_ids {
+        println!("{}", wait_for_exit_code(container_id)?);
+    }
+    Ok(())
+}
+
+// The records are polled since the container is supervised by another
+// process. A container which is restarted is done waiting for once it has
+// exited, which is when its finish time changes.
+fn wait_for_exit_code(container_id: &str) -> Result<i32> {
+    let mut first_finished_at = None;
+    loop {
+        let db = open_db()?;
+        let state = db.get(container_states_key(container_id))?;
+        let exit_code = db.get(container_exit_codes_key(container_id))?;
+        let finished_at = db.get(container_finished_at_key(container_id))?;
+        drop(db);
+
+        let state: ContainerState = match state {
+            Some(state) => String::from_utf8(state.to_vec())?.parse()?,
+            // Also when a container run with `--rm` is removed before its
+            // exit has been seen.
+            None => return Err(anyhow!("container not found: {}", container_id)),
+        };
+        let exit_code = match exit_code {
+            Some(code) => Some(String::from_utf8(code.to_vec())?.parse()?),
+            None => None,
+        };
+        let finished_at = finished_at.map(|f| f.to_vec());
+        let first_finished_at = first_finished_at.get_or_insert_with(|| finished_at.clone());
+
+        if let Some(code) = exit_code {
+            if state == ContainerState::Exited || finished_at != *first_finished_at {
+                return Ok(code);
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+pub fn pause_container(container_id: &str) -> Result<()> {
+    match fetch_container_state(container_id)? {
+        Some(ContainerState::Running) => {}
+        Some(_) => return Err(anyhow!("container is not running: {}", container_id)),
+        None => return Err(anyhow!("container not found: {}", container_id)),
+    }
+
+    freeze_cgroup(container_id)?;
+    set_container_state(container_id, ContainerState::Paused)?;
+    println!("{}", container_id);
+    Ok(())
+}
+
+pub fn unpause_container(container_id: &str) -> Result<()> {
+    match fetch_container_state(container_id)? {
+        Some(ContainerState::Paused) => {}
+        Some(_) => return Err(anyhow!("container is not paused: {}", container_id)),
+        None => return Err(anyhow!("container not found: {}", container_id)),
+    }
+
+    thaw_cgroup(container_id)?;
+    set_container_state(container_id, ContainerState::Running)?;
+    println!("{}", container_id);
+    Ok(())
+}
+
+// A frozen process only handles signals once it has been thawed.
+fn thaw_if_paused(container_id: &str) -> Result<()> {
+    if fetch_container_state(container_id)? == Some(ContainerState::Paused) {
+        thaw_cgroup(container_id)?;
+        set_container_state(container_id, ContainerState::Running)?;
+    }
+    Ok(())
+}
+
+pub async fn kill_container(container_id: &str, signal: &str) -> Result<()> {
+    let signal = parse_signal(signal)?;
+    let pid = match fetch_pid(&container_pids_key(container_id))? {
+        Some(pid) => pid,
+        None => {
+            println!("container not found: {}", &container_id);
+            return Ok(());
+        }
+    };
+
+    // Like `rocker stop`, killing the container keeps it from being restarted.
+    if signal == Signal::SIGKILL || signal == Signal::SIGTERM {
+        mark_manually_stopped(container_id)?;
+    }
+    if fetch_container_state(container_id)? != Some(ContainerState::Restarting) {
+        send_signal(pid, signal)?;
+        thaw_if_paused(container_id)?;
+    }
+
+    // Nobody is left to clean up after the container if its supervisor is gone.
+    let supervisor_pid = fetch_pid(&container_supervisor_pids_key(container_id))?;
+    if !supervisor_pid.map_or(false, is_process_alive) {
+        wait_for_container_exit(container_id, pid, Duration::from_secs(1)).await?;
+    }
+
+    println!("{}", container_id);
+    Ok(())
+}
+
+// Waits until the supervisor has cleaned up after the container. If the
+// supervisor has died, the cleanup is done here once the container is gone.
+async fn wait_for_container_exit(container_id: &str, pid: Pid, timeout: Duration) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let supervisor_pid = fetch_pid(&container_supervisor_pids_key(container_id))?;
+        match supervisor_pid {
+            None => return Ok(true),
+            Some(supervisor_pid) => {
+                if !is_process_alive(supervisor_pid) && !is_process_alive(pid) {
+                    // The real exit status was lost along with the supervisor.
+                    record_exit_code(container_id, 255)?;
+                    cleanup_container(container_id).await?;
+                    return Ok(true);
+                }
+            }
+        }
+
+        if Instant::now() >= deadline {
+            return Ok(false);
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+pub fn fetch_pid(key: &str) -> Result<Option<Pid>> {
+    let db = open_db()?;
+    match db.get(key)? {
+        Some(pid) => {
+            let pid: i32 = String::from_utf8(pid.to_vec())?.parse()?;
+            Ok(Some(Pid::from_raw(pid)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn is_process_alive(pid: Pid) -> bool {
+    kill(pid, None).is_ok()
+}
+
+fn send_signal(pid: Pid, signal: Signal) -> Result<()> {
+    match kill(pid, signal) {
+        // The container has already exited.
+        Err(nix::Error::Sys(nix::errno::Errno::ESRCH)) => Ok(()),
+        res => Ok(res?),
+    }
+}
+
+// Accepts "SIGTERM", "TERM" or "15".
+fn parse_signal(signal: &str) -> Result<Signal> {
+    if let Ok(num) = signal.parse::<i32>() {
+        return Signal::try_from(num).with_context(|| format!("Invalid signal: {}", signal));
+    }
+
+    let name = signal.to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    Signal::from_str(&name).with_context(|| format!("Invalid signal: {}", signal))
+}
+
+// Replaces the current process with the command. Must be called in the
+// container's root so that the command is looked up in its filesystem.
+// Only returns (with the exit code) when the command cannot be executed.
+fn exec_command(process: &Process) -> isize {
+    let command = &process.args;
+    let path = match find_executable(&command[0], process.path_env()) {
+        Some(path) => path,
+        None => {
+            eprintln!("executable file not found in $PATH: {}", &command[0]);
+            return 127;
+        }
+    };
+
+    let args: Vec<CString> = command
+        .iter()
+        .map(|arg| CString::new(arg.as_str()).unwrap())
+        .collect();
+    let env: Vec<CString> = process
+        .env
+        .iter()
+        .map(|e| CString::new(e.as_str()).unwrap())
+        .collect();
+    let err = execve(&CString::new(path).unwrap(), &args, &env).unwrap_err();
+    eprintln!("failed to exec {}: {}", &command[0], err);
+    126
+}
+
+// Builds "KEY=VALUE" entries from `--env-file`s and `-e`s (which take
+// precedence). "KEY" alone passes the variable through from the host and
+// is skipped when the host doesn't have it.
+pub fn parse_env_options(env: Vec<String>, env_files: Vec<String>) -> Result<Vec<String>> {
+    let mut entries = Vec::new();
+    for env_file in env_files {
+        let content = fs::read_to_string(&env_file)
+            .with_context(|| format!("Failed to read env file: {}", env_file))?;
+        entries.extend(
+            content
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                .map(|line| line.to_string()),
+        );
+    }
+    entries.extend(env);
+
+    let mut parsed = Vec::new();
+    for entry in entries {
+        if entry.starts_with('=') {
+            return Err(anyhow!("Invalid environment variable: {}", entry));
+        }
+        if entry.contains('=') {
+            parsed.push(entry);
+        } else if let Ok(value) = std::env::var(&entry) {
+            parsed.push(format!("{}={}", entry, value));
+        }
+    }
+    Ok(parsed)
+}
+
+// Sets each "KEY=VALUE" in `overrides`, replacing an existing entry for KEY.
+fn merge_env(env: &mut Vec<String>, overrides: &[String]) {
+    for entry in overrides {
+        let key = entry.splitn(2, '=').next().unwrap();
+        match env
+            .iter_mut()
+            .find(|e| e.splitn(2, '=').next().unwrap() == key)
+        {
+            Some(e) => *e = entry.clone(),
+            None => env.push(entry.clone()),
+        }
+    }
+}
+
+// Docker creates the working directory when the image doesn't have it.
+fn create_working_dir(rootfs: &str, cwd: &str) -> Result<()> {
+    create_dir_all(resolve_in_root(Path::new(rootfs), cwd, true)?)?;
+    Ok(())
+}
+
+fn enter_working_dir(cwd: &str) -> Result<()> {
+    chdir(cwd)?;
+    Ok(())
+}
+
+fn find_executable(name: &str, path_env: &str) -> Option<String> {
+    if name.contains('/') {
+        return Some(name.to_string());
+    }
+
+    for dir in path_env.split(':') {
+        let path = Path::new(dir).join(name);
+        if let Ok(metadata) = fs::metadata(&path) {
+            if metadata.is_file() && metadata.permissions().mode() & 0o111 != 0 {
+                return Some(path.to_string_lossy().to_string());
+            }
+        }
+    }
+    None
+}
+
 fn setns_by_fd_path(path: &str, nstype: CloneFlags) -> Result<()> {
     let mut oflag = OFlag::empty();
     oflag.insert(OFlag::O_RDONLY);
@@ -0,0 +1,244 @@
+use std::{
+    fs::{self, Permissions},
+    io::{self, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::{fchownat, FchownatFlags, Gid, Pid, Uid};
+use tar::{Archive, Builder, EntryType};
+
+use crate::{
+    cgroup::{freeze_cgroup, thaw_cgroup},
+    container::{
+        container_overlay_options, fetch_container_state, fetch_pid, mount_overlay_fs_privately,
+        ContainerState,
+    },
+    db::container_pids_key,
+    mount::resolve_in_root,
+    rootless::{is_rootless, run_in_container_user_namespace, run_in_user_namespace},
+    volume::{copy_attributes, copy_dir, copy_file},
+    ROCKER_CONTAINERS_PATH,
+};
+
+enum CopyPath {
+    Container { id: String, path: String },
+    Host(String),
+    // A tar archive on stdin or stdout.
+    Stream,
+}
+
+// Like Docker, a path which starts with "." or has "/" before the first ':'
+// is a host path.
+fn parse_copy_path(arg: &str) -> CopyPath {
+    if arg == "-" {
+        return CopyPath::Stream;
+    }
+    if let Some(i) = arg.find(':') {
+        if i > 0 && !arg.starts_with('.') && !arg[..i].contains('/') {
+            return CopyPath::Container {
+                id: arg[..i].to_string(),
+                path: arg[i + 1..].to_string(),
+            };
+        }
+    }
+    CopyPath::Host(arg.to_string())
+}
+
+// Copies files between the container and the host, in either direction, the
+// same way as `docker cp`. "-" stands for a tar archive on stdout or stdin.
+pub fn copy_files(src: &str, dst: &str) -> Result<()> {
+    let src = parse_copy_path(src);
+    let dst = parse_copy_path(dst);
+    let container_id = match (&src, &dst) {
+        (CopyPath::Container { id, .. }, CopyPath::Host(_))
+        | (CopyPath::Container { id, .. }, CopyPath::Stream)
+        | (CopyPath::Host(_), CopyPath::Container { id, .. })
+        | (CopyPath::Stream, CopyPath::Container { id, .. }) => id,
+        _ => {
+            return Err(anyhow!(
+                "Either the source or the destination must be a path in a container (<container-id>:<path>)"
+            ))
+        }
+    };
+    let state = match fetch_container_state(container_id)? {
+        Some(state) => state,
+        None => return Err(anyhow!("container not found: {}", container_id)),
+    };
+    let pid = match state {
+        ContainerState::Running | ContainerState::Paused => {
+            fetch_pid(&container_pids_key(container_id))?
+        }
+        _ => None,
+    };
+    let root = container_root(container_id, &state, pid)?;
+    // A stopped container's overlay is mounted only for the copy.
+    let overlay_options = match state {
+        ContainerState::Created | ContainerState::Exited => {
+            Some(container_overlay_options(container_id)?)
+        }
+        _ => None,
+    };
+
+    let copy = || {
+        if let Some(options) = &overlay_options {
+            mount_overlay_fs_privately(container_id, options)?;
+        }
+        match (&src, &dst) {
+            (CopyPath::Container { path, .. }, CopyPath::Host(host_path)) => copy_into(
+                &resolve_in_root(&root, path, false)?,
+                is_contents_only(path),
+                Path::new(host_path),
+                host_path.ends_with('/'),
+            ),
+            (CopyPath::Container { path, .. }, _) => write_archive(
+                &resolve_in_root(&root, path, false)?,
+                is_contents_only(path),
+            ),
+            (CopyPath::Host(host_path), CopyPath::Container { path, .. }) => copy_into(
+                Path::new(host_path),
+                is_contents_only(host_path),
+                &resolve_in_root(&root, path, true)?,
+                path.ends_with('/'),
+            ),
+            (_, CopyPath::Container { path, .. }) => {
+                extract_archive(&resolve_in_root(&root, path, true)?)
+            }
+            _ => Ok(()),
+        }
+    };
+
+    // The paths are resolved before they are copied from or to, so a running
+    // container is frozen in the meantime. Otherwise it could replace a
+    // directory on the way with a symlink to anywhere on the host.
+    let freeze = state == ContainerState::Running;
+    if freeze {
+        freeze_cgroup(container_id)?;
+    }
+    // Files in a rootless container may be owned by subordinate IDs, and
+    // only the container's own user namespace can reach into its processes.
+    let result = match pid {
+        Some(pid) if is_rootless() => run_in_container_user_namespace(pid, copy),
+        _ if is_rootless() => run_in_user_namespace(copy),
+        _ => copy(),
+    };
+    if freeze {
+        thaw_cgroup(container_id)?;
+    }
+    result
+}
+
+// The root of the container's process while it runs, through which its
+// volumes, tmpfs mounts and /etc files are seen as well. Otherwise it's where
+// the overlay is mounted for the copy.
+fn container_root(container_id: &str, state: &ContainerState, pid: Option<Pid>) -> Result<PathBuf> {
+    match (state, pid) {
+        // It can't be frozen while it's being started again.
+        (ContainerState::Restarting, _) => Err(anyhow!(
+            "container is restarting, wait until it is running: {}",
+            container_id
+        )),
+        (ContainerState::Running, Some(pid)) | (ContainerState::Paused, Some(pid)) => {
+            Ok(PathBuf::from(format!("/proc/{}/root", pid)))
+        }
+        (ContainerState::Running, None) | (ContainerState::Paused, None) => {
+            Err(anyhow!("container is not running: {}", container_id))
+        }
+        (ContainerState::Created, _) | (ContainerState::Exited, _) => {
+            Ok(Path::new(&*ROCKER_CONTAINERS_PATH)
+                .join(container_id)
+                .join("fs/mnt"))
+        }
+    }
+}
+
+// Only the contents of a directory are copied if its path ends with "/.".
+fn is_contents_only(path: &str) -> bool {
+    path == "." || path.ends_with("/.")
+}
+
+// Copies `src` into `dst` if it's a directory, or to `dst` otherwise,
+// preserving modes, ownership and symlinks.
+fn copy_into(src: &Path, contents_only: bool, dst: &Path, dst_is_dir: bool) -> Result<()> {
+    let metadata = fs::symlink_metadata(src)
+        .with_context(|| format!("No such file or directory: {}", src.display()))?;
+    let target = if dst.is_dir() {
+        match src.file_name() {
+            Some(name) if !contents_only => dst.join(name),
+            _ => dst.to_path_buf(),
+        }
+    } else if dst_is_dir && !metadata.is_dir() {
+        return Err(anyhow!("No such directory: {}", dst.display()));
+    } else {
+        dst.to_path_buf()
+    };
+    if !metadata.is_dir() && target.is_dir() {
+        return Err(anyhow!(
+            "Cannot overwrite a directory with a non-directory: {}",
+            target.display()
+        ));
+    }
+
+    // An existing symlink is replaced rather than followed.
+    if fs::symlink_metadata(&target).map_or(false, |m| m.file_type().is_symlink()) {
+        fs::remove_file(&target)?;
+    }
+    if metadata.is_dir() {
+        fs::create_dir_all(&target)?;
+        copy_dir(src, &target)?;
+    } else if !copy_file(src, &metadata, &target)? {
+        return Err(anyhow!("Cannot copy a socket: {}", src.display()));
+    }
+    copy_attributes(&metadata, &target)
+}
+
+fn write_archive(src: &Path, contents_only: bool) -> Result<()> {
+    let metadata = fs::symlink_metadata(src)
+        .with_context(|| format!("No such file or directory: {}", src.display()))?;
+    let name = match src.file_name() {
+        Some(name) if !contents_only => PathBuf::from(name),
+        _ => PathBuf::from("."),
+    };
+
+    let mut builder = Builder::new(io::stdout());
+    builder.follow_symlinks(false);
+    if metadata.is_dir() {
+        builder.append_dir_all(&name, src)?;
+    } else {
+        builder.append_path_with_name(src, &name)?;
+    }
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+// Entries which would be extracted outside of `dst` are skipped.
+fn extract_archive(dst: &Path) -> Result<()> {
+    if !dst.is_dir() {
+        return Err(anyhow!("No such directory: {}", dst.display()));
+    }
+
+    let mut archive = Archive::new(io::stdin());
+    archive.set_preserve_permissions(true);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if !entry.unpack_in(dst)? {
+            continue;
+        }
+
+        let header = entry.header();
+        let path = dst.join(entry.path()?);
+        fchownat(
+            None,
+            &path,
+            Some(Uid::from_raw(header.uid()? as u32)),
+            Some(Gid::from_raw(header.gid()? as u32)),
+            FchownatFlags::NoFollowSymlink,
+        )?;
+        // Changing the owner clears the setuid and setgid bits.
+        if header.entry_type() != EntryType::Symlink {
+            fs::set_permissions(&path, Permissions::from_mode(header.mode()?))?;
+        }
+    }
+    Ok(())
+}
@@ -1,7 +1,30 @@
+use std::{thread, time::Duration};
+
+use anyhow::Result;
+
+use crate::ROCKER_DB_PATH;
+
 const DOWNLOADED_IMAGES_KEY_PREFIX: &str = "downloaded_images";
 const CONTAINER_COMMANDS_KEY_PREFIX: &str = "container_commands";
+const CONTAINER_ENVS_KEY_PREFIX: &str = "container_envs";
 const CONTAINER_IMAGE_HASHES_KEY_PREFIX: &str = "container_image_hashes";
 const CONTAINER_PIDS_KEY_PREFIX: &str = "container_pids";
+const CONTAINER_SUPERVISOR_PIDS_KEY_PREFIX: &str = "container_supervisor_pids";
+const CONTAINER_STATES_KEY_PREFIX: &str = "container_states";
+const CONTAINER_EXIT_CODES_KEY_PREFIX: &str = "container_exit_codes";
+const CONTAINER_CREATED_AT_KEY_PREFIX: &str = "container_created_at";
+const CONTAINER_STARTED_AT_KEY_PREFIX: &str = "container_started_at";
+const CONTAINER_FINISHED_AT_KEY_PREFIX: &str = "container_finished_at";
+const CONTAINER_AUTO_REMOVES_KEY_PREFIX: &str = "container_auto_removes";
+const CONTAINER_MOUNTS_KEY_PREFIX: &str = "container_mounts";
+const CONTAINER_CAPABILITIES_KEY_PREFIX: &str = "container_capabilities";
+const CONTAINER_SECCOMP_PROFILES_KEY_PREFIX: &str = "container_seccomp_profiles";
+const CONTAINER_USERS_KEY_PREFIX: &str = "container_users";
+const CONTAINER_WORKING_DIRS_KEY_PREFIX: &str = "container_working_dirs";
+const CONTAINER_RESTART_POLICIES_KEY_PREFIX: &str = "container_restart_policies";
+const CONTAINER_RESTART_COUNTS_KEY_PREFIX: &str = "container_restart_counts";
+const CONTAINER_MANUAL_STOPS_KEY_PREFIX: &str = "container_manual_stops";
+const VOLUMES_KEY_PREFIX: &str = "volumes";
 const USED_IP_ADDRESSES_KEY_PREFIX: &str = "used_ip_addresses";
 const VETH_IP_ADDRESSES_KEY_PREFIX: &str = "veth_ip_addresses";
 
@@ -15,6 +38,11 @@ pub fn container_commands_key(key: &str) -> String {
     format!("{}/{}", CONTAINER_COMMANDS_KEY_PREFIX, key)
 }
 
+// container_id => environment variables (JSON array of "KEY=VALUE")
+pub fn container_envs_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_ENVS_KEY_PREFIX, key)
+}
+
 // container_id => image_hash
 pub fn container_image_hashes_key(key: &str) -> String {
     format!("{}/{}", CONTAINER_IMAGE_HASHES_KEY_PREFIX, key)
@@ -25,6 +53,86 @@ pub fn container_pids_key(key: &str) -> String {
     format!("{}/{}", CONTAINER_PIDS_KEY_PREFIX, key)
 }
 
+// container_id => pid of the process which waits for the container
+pub fn container_supervisor_pids_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_SUPERVISOR_PIDS_KEY_PREFIX, key)
+}
+
+// container_id => state (created, running, paused or exited)
+pub fn container_states_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_STATES_KEY_PREFIX, key)
+}
+
+// container_id => exit code
+pub fn container_exit_codes_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_EXIT_CODES_KEY_PREFIX, key)
+}
+
+// container_id => creation time (RFC 3339)
+pub fn container_created_at_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_CREATED_AT_KEY_PREFIX, key)
+}
+
+// container_id => start time (RFC 3339)
+pub fn container_started_at_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_STARTED_AT_KEY_PREFIX, key)
+}
+
+// container_id => finish time (RFC 3339)
+pub fn container_finished_at_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_FINISHED_AT_KEY_PREFIX, key)
+}
+
+// container_id => "1" if the container is removed when it exits (--rm)
+pub fn container_auto_removes_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_AUTO_REMOVES_KEY_PREFIX, key)
+}
+
+// container_id => mounts (JSON array)
+pub fn container_mounts_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_MOUNTS_KEY_PREFIX, key)
+}
+
+// container_id => capabilities (JSON array of "CAP_...")
+pub fn container_capabilities_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_CAPABILITIES_KEY_PREFIX, key)
+}
+
+// container_id => seccomp profile (JSON, null if unconfined)
+pub fn container_seccomp_profiles_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_SECCOMP_PROFILES_KEY_PREFIX, key)
+}
+
+// container_id => user the process runs as ("user[:group]", empty for root)
+pub fn container_users_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_USERS_KEY_PREFIX, key)
+}
+
+// container_id => working directory of the process
+pub fn container_working_dirs_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_WORKING_DIRS_KEY_PREFIX, key)
+}
+
+// container_id => restart policy ("no", "on-failure[:N]", "always" or "unless-stopped")
+pub fn container_restart_policies_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_RESTART_POLICIES_KEY_PREFIX, key)
+}
+
+// container_id => number of times the container has been restarted
+pub fn container_restart_counts_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_RESTART_COUNTS_KEY_PREFIX, key)
+}
+
+// container_id => "1" if the container has been stopped with `rocker stop` or `rocker kill`
+pub fn container_manual_stops_key(key: &str) -> String {
+    format!("{}/{}", CONTAINER_MANUAL_STOPS_KEY_PREFIX, key)
+}
+
+// volume name => creation time (RFC 3339)
+pub fn volumes_key(key: &str) -> String {
+    format!("{}/{}", VOLUMES_KEY_PREFIX, key)
+}
+
 pub fn used_ip_addresses_key(key: &str) -> String {
     format!("{}/{}", USED_IP_ADDRESSES_KEY_PREFIX, key)
 }
@@ -33,3 +141,23 @@ pub fn used_ip_addresses_key(key: &str) -> String {
 pub fn veth_ip_addresses_key(key: &str) -> String {
     format!("{}/{}", VETH_IP_ADDRESSES_KEY_PREFIX, key)
 }
+
+// sled holds an exclusive lock on the database while it is open, so another
+// rocker process (e.g. the supervisor of a detached container) may have it
+// for a moment. Retry for a while before giving up.
+pub fn open_db() -> Result<sled::Db> {
+    const MAX_RETRIES: u32 = 50;
+    let mut retries = 0;
+    loop {
+        match sled::open(&*ROCKER_DB_PATH) {
+            Ok(db) => return Ok(db),
+            Err(e) => {
+                if retries >= MAX_RETRIES {
+                    return Err(e.into());
+                }
+                retries += 1;
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+    }
+}
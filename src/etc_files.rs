@@ -0,0 +1,164 @@
+use std::{fs, net::IpAddr};
+
+use anyhow::{anyhow, Result};
+
+use crate::{mount::Mount, rootless::is_rootless, ROCKER_CONTAINERS_PATH};
+
+const DEFAULT_NAMESERVERS: [&str; 2] = ["8.8.8.8", "8.8.4.4"];
+
+// Options of `rocker run` for the generated /etc/hosts and /etc/resolv.conf.
+pub struct EtcFilesOptions {
+    pub dns: Vec<String>,
+    pub dns_search: Vec<String>,
+    // (hostname, IP address) given with `--add-host`.
+    pub extra_hosts: Vec<(String, String)>,
+}
+
+pub fn parse_dns_options(dns: Vec<String>) -> Result<Vec<String>> {
+    for server in &dns {
+        server
+            .parse::<IpAddr>()
+            .map_err(|_| anyhow!("Invalid DNS server address: {}", server))?;
+    }
+    Ok(dns)
+}
+
+// Parses "hostname:ip". The IP address may be an IPv6 one, which contains ':'.
+pub fn parse_add_host_options(hosts: Vec<String>) -> Result<Vec<(String, String)>> {
+    let mut extra_hosts = Vec::new();
+    for host in &hosts {
+        let (name, ip) = match host.find(':') {
+            Some(i) => (&host[..i], &host[i + 1..]),
+            None => return Err(anyhow!("Invalid --add-host (host:ip): {}", host)),
+        };
+        if name.is_empty() || ip.parse::<IpAddr>().is_err() {
+            return Err(anyhow!("Invalid --add-host (host:ip): {}", host));
+        }
+        extra_hosts.push((name.to_string(), ip.to_string()));
+    }
+    Ok(extra_hosts)
+}
+
+// Writes /etc/hostname, /etc/hosts and /etc/resolv.conf for the container in
+// its directory, and returns the mounts which bind them into its rootfs. A
+// symlink in the image at one of these paths (often /etc/resolv.conf) is
+// replaced by the mount point rather than followed.
+pub fn create_etc_files(
+    container_id: &str,
+    hostname: &str,
+    ip_addr: Option<&str>,
+    options: &EtcFilesOptions,
+) -> Result<Vec<Mount>> {
+    let container_path = format!("{}/{}", *ROCKER_CONTAINERS_PATH, container_id);
+    let files = [
+        ("hostname", format!("{}\n", hostname)),
+        (
+            "hosts",
+            build_hosts(hostname, ip_addr, &options.extra_hosts),
+        ),
+        ("resolv.conf", build_resolv_conf(options)),
+    ];
+
+    let mut mounts = Vec::new();
+    for (name, content) in files.iter() {
+        let path = format!("{}/{}", container_path, name);
+        fs::write(&path, content)?;
+        mounts.push(Mount {
+            name: None,
+            source: path,
+            destination: format!("/etc/{}", name),
+            read_only: false,
+        });
+    }
+    Ok(mounts)
+}
+
+fn build_hosts(hostname: &str, ip_addr: Option<&str>, extra_hosts: &[(String, String)]) -> String {
+    let mut hosts = String::from(
+        "127.0.0.1\tlocalhost\n\
+         ::1\tlocalhost ip6-localhost ip6-loopback\n\
+         fe00::0\tip6-localnet\n\
+         ff00::0\tip6-mcastprefix\n\
+         ff02::1\tip6-allnodes\n\
+         ff02::2\tip6-allrouters\n",
+    );
+    for (name, ip) in extra_hosts {
+        hosts.push_str(&format!("{}\t{}\n", ip, name));
+    }
+    // A rootless container has no address of its own.
+    hosts.push_str(&format!(
+        "{}\t{}\n",
+        ip_addr.unwrap_or("127.0.1.1"),
+        hostname
+    ));
+    hosts
+}
+
+// Based on the host's resolv.conf. Nameservers on the loopback address (like
+// systemd-resolved's 127.0.0.53) can't be reached from the container's network
+// namespace, so the upstream ones are used instead, or public ones as a last
+// resort.
+fn build_resolv_conf(options: &EtcFilesOptions) -> String {
+    let host_conf = fs::read_to_string("/etc/resolv.conf").unwrap_or_default();
+
+    let nameservers = if !options.dns.is_empty() {
+        options.dns.clone()
+    } else if is_rootless() {
+        // Rootless containers share the host's network.
+        find_entries(&host_conf, "nameserver")
+    } else {
+        let mut nameservers = reachable_nameservers(&host_conf);
+        if nameservers.is_empty() {
+            let upstream =
+                fs::read_to_string("/run/systemd/resolve/resolv.conf").unwrap_or_default();
+            nameservers = reachable_nameservers(&upstream);
+        }
+        if nameservers.is_empty() {
+            nameservers = DEFAULT_NAMESERVERS.iter().map(|s| s.to_string()).collect();
+        }
+        nameservers
+    };
+
+    // Only the last "search" line counts.
+    let search = if !options.dns_search.is_empty() {
+        options.dns_search.join(" ")
+    } else {
+        find_entries(&host_conf, "search").pop().unwrap_or_default()
+    };
+
+    let mut conf = String::new();
+    if !search.is_empty() {
+        conf.push_str(&format!("search {}\n", search));
+    }
+    for nameserver in nameservers {
+        conf.push_str(&format!("nameserver {}\n", nameserver));
+    }
+    for host_options in find_entries(&host_conf, "options") {
+        conf.push_str(&format!("options {}\n", host_options));
+    }
+    conf
+}
+
+fn reachable_nameservers(conf: &str) -> Vec<String> {
+    find_entries(conf, "nameserver")
+        .into_iter()
+        .filter(|ns| {
+            ns.parse::<IpAddr>()
+                .map(|ip| !ip.is_loopback())
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
+// The rest of each line starting with `keyword`.
+fn find_entries(conf: &str, keyword: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    for line in conf.lines() {
+        let mut words = line.split_whitespace();
+        if words.next() != Some(keyword) {
+            continue;
+        }
+        entries.push(words.collect::<Vec<&str>>().join(" "));
+    }
+    entries
+}
@@ -1,16 +1,18 @@
 use super::{
-    container::fetch_running_containers, db::downloaded_images_key, ROCKER_DB_PATH,
+    container::fetch_containers,
+    db::{downloaded_images_key, open_db},
     ROCKER_IMAGES_PATH, ROCKER_TMP_PATH,
 };
-use std::{fs, io::Write};
+use std::{fs, io::Write, path::Path};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use dkregistry::v2::{
     manifest::{Manifest, ManifestSchema2},
     Client,
 };
 use flate2::read::GzDecoder;
 use futures::future::join_all;
+use serde::{Deserialize, Serialize};
 use tar::Archive;
 
 struct Image {
@@ -19,11 +21,28 @@ struct Image {
     tag: String,
 }
 
+// The parts of the image config blob that affect how a container is run.
+// https://github.com/opencontainers/image-spec/blob/master/config.md
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "PascalCase")]
+pub struct ImageConfig {
+    pub entrypoint: Option<Vec<String>>,
+    pub cmd: Option<Vec<String>>,
+    pub env: Option<Vec<String>>,
+    pub working_dir: Option<String>,
+    pub user: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ImageConfigBlob {
+    config: Option<ImageConfig>,
+}
+
 pub async fn download_image_if_needed(
     image_name: &str,
     username: Option<String>,
     password: Option<String>,
-) -> Result<(String, ManifestSchema2)> {
+) -> Result<String> {
     let (image_name, tag) = parse_image_name(&image_name)?;
     println!("Downloading metadata for {}:{}", image_name, tag);
 
@@ -46,7 +65,7 @@ pub async fn download_image_if_needed(
 
     let image_hash = s2_manifest.manifest_spec.config().digest[7..=18].to_string();
 
-    let db = sled::open(ROCKER_DB_PATH).unwrap();
+    let db = open_db()?;
     if !is_image_already_downloaded(&db, &image_hash)? {
         let image_layer_digests = s2_manifest.get_layers();
         println!("Downloading image {}:{}...", image_name, tag);
@@ -60,7 +79,58 @@ pub async fn download_image_if_needed(
         println!("Image already exists");
     }
 
-    Ok((image_hash, s2_manifest))
+    // Images downloaded by older versions of rocker don't have the config yet.
+    if !Path::new(&image_config_path(&image_hash)).exists() {
+        let config_digest = &s2_manifest.manifest_spec.config().digest;
+        download_image_config(&dclient, &image_name, &image_hash, config_digest).await?;
+    }
+    // Nor the order of the layers.
+    if !Path::new(&image_layers_path(&image_hash)).exists() {
+        save_image_layers(&image_hash, &s2_manifest.get_layers())?;
+    }
+
+    Ok(image_hash)
+}
+
+fn image_config_path(image_hash: &str) -> String {
+    format!("{}/{}/config.json", *ROCKER_IMAGES_PATH, image_hash)
+}
+
+fn image_layers_path(image_hash: &str) -> String {
+    format!("{}/{}/layers.json", *ROCKER_IMAGES_PATH, image_hash)
+}
+
+// The layer directories are named after the digests, so their order has to
+// be kept apart.
+fn save_image_layers(image_hash: &str, image_layer_digests: &[String]) -> Result<()> {
+    let layers: Vec<&str> = image_layer_digests
+        .iter()
+        .map(|digest| &digest[7..=18])
+        .collect();
+    fs::write(
+        image_layers_path(image_hash),
+        serde_json::to_string(&layers)?,
+    )?;
+    Ok(())
+}
+
+async fn download_image_config(
+    client: &Client,
+    image_name: &str,
+    image_hash: &str,
+    config_digest: &str,
+) -> Result<()> {
+    let blob = client.get_blob(image_name, config_digest).await?;
+    fs::create_dir_all(format!("{}/{}", *ROCKER_IMAGES_PATH, image_hash))?;
+    fs::write(image_config_path(image_hash), blob)?;
+    Ok(())
+}
+
+pub fn load_image_config(image_hash: &str) -> Result<ImageConfig> {
+    let path = image_config_path(image_hash);
+    let blob = fs::read(&path).with_context(|| format!("Failed to read image config: {}", path))?;
+    let config: ImageConfigBlob = serde_json::from_slice(&blob)?;
+    Ok(config.config.unwrap_or_default())
 }
 
 fn is_image_already_downloaded(image_hash_table: &sled::Tree, image_hash: &str) -> Result<bool> {
@@ -110,7 +180,7 @@ async fn download_layers_blob(
     image_hash: &str,
     image_layer_digests: &Vec<String>,
 ) -> Result<()> {
-    let image_layers_tar_path = format!("{}{}{}", ROCKER_TMP_PATH, "/", image_hash);
+    let image_layers_tar_path = format!("{}{}{}", *ROCKER_TMP_PATH, "/", image_hash);
     fs::create_dir_all(&image_layers_tar_path)?;
     let mut pull_tasks = Vec::new();
     for layer_digest in image_layer_digests {
@@ -138,8 +208,8 @@ async fn download_layers_blob(
 
 fn extract_layers(image_hash: &str, image_layer_digests: &Vec<String>) -> Result<()> {
     println!("Extract layers...");
-    let image_layers_tar_path = format!("{}{}{}", ROCKER_TMP_PATH, "/", image_hash);
-    let image_layers_dst_path = format!("{}{}{}", ROCKER_IMAGES_PATH, "/", image_hash,);
+    let image_layers_tar_path = format!("{}{}{}", *ROCKER_TMP_PATH, "/", image_hash);
+    let image_layers_dst_path = format!("{}{}{}", *ROCKER_IMAGES_PATH, "/", image_hash,);
 
     for layer_digest in image_layer_digests {
         // https://rust-lang-nursery.github.io/rust-cookbook/compression/tar.html
@@ -166,7 +236,7 @@ fn extract_layers(image_hash: &str, image_layer_digests: &Vec<String>) -> Result
 }
 
 fn delete_temp_image_files(image_hash: &str) -> Result<()> {
-    let path = format!("{}{}{}", ROCKER_TMP_PATH, "/", image_hash);
+    let path = format!("{}{}{}", *ROCKER_TMP_PATH, "/", image_hash);
     fs::remove_dir_all(path)?;
     Ok(())
 }
@@ -183,8 +253,8 @@ pub fn print_available_images() -> Result<()> {
 fn fetch_available_images() -> Result<Vec<Image>> {
     let mut images = Vec::new();
 
-    let db = sled::open(ROCKER_DB_PATH)?;
-    for entry in fs::read_dir(ROCKER_IMAGES_PATH)? {
+    let db = open_db()?;
+    for entry in fs::read_dir(&*ROCKER_IMAGES_PATH)? {
         let path = entry?.path();
         let image_hash = path.file_name().unwrap().to_string_lossy().to_string();
 
@@ -202,18 +272,46 @@ fn fetch_available_images() -> Result<Vec<Image>> {
     Ok(images)
 }
 
+// Finds a downloaded image by its hash or "name[:tag]". Returns the hash and
+// "name:tag".
+pub fn find_image(name: &str) -> Result<Option<(String, String)>> {
+    let name_and_tag = parse_image_name(name)
+        .map(|(name, tag)| format!("{}:{}", name, tag))
+        .ok();
+    for image in fetch_available_images()? {
+        let image_name_and_tag = format!("{}:{}", image.name, image.tag);
+        if image.image_hash == name || Some(&image_name_and_tag) == name_and_tag.as_ref() {
+            return Ok(Some((image.image_hash, image_name_and_tag)));
+        }
+    }
+    Ok(None)
+}
+
+// The directories of the image's layers in the manifest's order, which are
+// the lower directories of its containers' overlays in the same order.
+pub fn fetch_image_layers(image_hash: &str) -> Result<Vec<String>> {
+    let path = image_layers_path(image_hash);
+    let content = fs::read_to_string(&path)
+        .with_context(|| format!("Failed to read image layers: {}", path))?;
+    let layers: Vec<String> = serde_json::from_str(&content)?;
+    Ok(layers
+        .iter()
+        .map(|layer| format!("{}/{}/{}/fs", *ROCKER_IMAGES_PATH, image_hash, layer))
+        .collect())
+}
+
 pub fn delete_image(image_hash: &str) -> Result<()> {
     let _is_used_by_container = false;
-    for container in fetch_running_containers()? {
+    for container in fetch_containers()? {
         if container.image_hash == image_hash {
-            println!("image is being used by running container: {}", container.id);
+            println!("image is being used by container: {}", container.id);
             return Ok(());
         }
     }
 
-    fs::remove_dir_all(format!("{}/{}", ROCKER_IMAGES_PATH, image_hash))?;
+    fs::remove_dir_all(format!("{}/{}", *ROCKER_IMAGES_PATH, image_hash))?;
 
-    let db = sled::open(ROCKER_DB_PATH)?;
+    let db = open_db()?;
     db.remove(downloaded_images_key(image_hash))?;
     Ok(())
 }
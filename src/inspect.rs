@@ -0,0 +1,382 @@
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{
+    cgroup::fetch_cgroup_paths,
+    container::fetch_container_state,
+    db::{
+        container_auto_removes_key, container_capabilities_key, container_commands_key,
+        container_created_at_key, container_envs_key, container_exit_codes_key,
+        container_finished_at_key, container_image_hashes_key, container_mounts_key,
+        container_pids_key, container_restart_counts_key, container_restart_policies_key,
+        container_seccomp_profiles_key, container_started_at_key, container_states_key,
+        container_users_key, container_working_dirs_key, downloaded_images_key, open_db,
+    },
+    image::{fetch_image_layers, find_image, load_image_config, ImageConfig},
+    logs::log_path,
+    mount::Mount,
+    network::fetch_ip_address,
+    rootless::is_rootless,
+    ROCKER_CONTAINERS_PATH, ROCKER_NETNS_PATH,
+};
+
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Details {
+    Container(ContainerDetails),
+    Image(ImageDetails),
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerDetails {
+    id: String,
+    created: String,
+    path: String,
+    args: Vec<String>,
+    state: StateDetails,
+    image: String,
+    log_path: String,
+    hostname_path: String,
+    hosts_path: String,
+    resolv_conf_path: String,
+    restart_count: u32,
+    config: ConfigDetails,
+    host_config: HostConfigDetails,
+    graph_driver: GraphDriverDetails,
+    mounts: Vec<Mount>,
+    network_settings: NetworkDetails,
+    cgroup_paths: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct StateDetails {
+    status: String,
+    running: bool,
+    paused: bool,
+    restarting: bool,
+    // 0 when the container isn't running, like Docker.
+    pid: i32,
+    exit_code: i32,
+    started_at: Option<String>,
+    finished_at: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ConfigDetails {
+    hostname: String,
+    user: String,
+    env: Vec<String>,
+    cmd: Vec<String>,
+    image: String,
+    working_dir: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct HostConfigDetails {
+    auto_remove: bool,
+    restart_policy: String,
+    capabilities: Vec<String>,
+    security_opt: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct GraphDriverDetails {
+    name: String,
+    upper_dir: String,
+    work_dir: String,
+    merged_dir: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct NetworkDetails {
+    // Empty for a rootless container, which shares the host's network.
+    sandbox_key: String,
+    #[serde(rename = "IPAddress")]
+    ip_address: String,
+    bridge_veth: String,
+    container_veth: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct ImageDetails {
+    id: String,
+    repo_tags: Vec<String>,
+    config: ImageConfig,
+    layers: Vec<String>,
+}
+
+// Prints the details of each container or image as a JSON array, or renders
+// `format` for each of them on its own line. A name is looked up as a
+// container ID first, then as an image hash or "name[:tag]".
+pub fn inspect(names: &[String], format: Option<&str>) -> Result<()> {
+    let mut found = Vec::new();
+    for name in names {
+        found.push(inspect_object(name)?);
+    }
+
+    match format {
+        Some(format) => {
+            let template = parse_template(format)?;
+            for details in &found {
+                let document = serde_json::to_value(details)?;
+                println!("{}", render_template(&template, &document)?);
+            }
+        }
+        None => println!("{}", serde_json::to_string_pretty(&found)?),
+    }
+    Ok(())
+}
+
+fn inspect_object(name: &str) -> Result<Details> {
+    if fetch_container_state(name)?.is_some() {
+        return Ok(Details::Container(inspect_container(name)?));
+    }
+    match find_image(name)? {
+        Some((image_hash, name_and_tag)) => {
+            Ok(Details::Image(inspect_image(&image_hash, &name_and_tag)?))
+        }
+        None => Err(anyhow!("No such object: {}", name)),
+    }
+}
+
+fn inspect_container(container_id: &str) -> Result<ContainerDetails> {
+    let db = open_db()?;
+    let get = |key: String| -> Result<Option<String>> {
+        match db.get(key)? {
+            Some(value) => Ok(Some(String::from_utf8(value.to_vec())?)),
+            None => Ok(None),
+        }
+    };
+
+    let command: Vec<String> = match get(container_commands_key(container_id))? {
+        Some(command) => serde_json::from_str(&command)?,
+        None => Vec::new(),
+    };
+    let env: Vec<String> = match get(container_envs_key(container_id))? {
+        Some(env) => serde_json::from_str(&env)?,
+        None => Vec::new(),
+    };
+    let capabilities: Vec<String> = match get(container_capabilities_key(container_id))? {
+        Some(caps) => serde_json::from_str(&caps)?,
+        None => Vec::new(),
+    };
+    let mounts: Vec<Mount> = match get(container_mounts_key(container_id))? {
+        Some(mounts) => serde_json::from_str(&mounts)?,
+        None => Vec::new(),
+    };
+    // The profile is stored as null when seccomp is disabled.
+    let security_opt = match get(container_seccomp_profiles_key(container_id))?.as_deref() {
+        Some("null") => vec!["seccomp=unconfined".to_string()],
+        _ => Vec::new(),
+    };
+
+    let image_hash = get(container_image_hashes_key(container_id))?.unwrap_or_default();
+    let image_name = get(downloaded_images_key(&image_hash))?.unwrap_or_default();
+    let status = get(container_states_key(container_id))?.unwrap_or_else(|| "created".to_string());
+    let pid = get(container_pids_key(container_id))?
+        .and_then(|pid| pid.parse().ok())
+        .unwrap_or(0);
+    let exit_code = get(container_exit_codes_key(container_id))?
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0);
+    let restart_count = get(container_restart_counts_key(container_id))?
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+
+    let state = StateDetails {
+        running: status == "running" || status == "paused",
+        paused: status == "paused",
+        restarting: status == "restarting",
+        pid: pid,
+        exit_code: exit_code,
+        started_at: get(container_started_at_key(container_id))?,
+        finished_at: get(container_finished_at_key(container_id))?,
+        status: status,
+    };
+    let host_config = HostConfigDetails {
+        auto_remove: get(container_auto_removes_key(container_id))?.is_some(),
+        restart_policy: get(container_restart_policies_key(container_id))?
+            .unwrap_or_else(|| "no".to_string()),
+        capabilities: capabilities,
+        security_opt: security_opt,
+    };
+    let created = get(container_created_at_key(container_id))?.unwrap_or_default();
+    let user = get(container_users_key(container_id))?.unwrap_or_default();
+    let working_dir = get(container_working_dirs_key(container_id))?.unwrap_or_default();
+    drop(db);
+
+    let container_path = format!("{}/{}", *ROCKER_CONTAINERS_PATH, container_id);
+    let hostname = fs::read_to_string(format!("{}/hostname", container_path))
+        .map(|hostname| hostname.trim().to_string())
+        .unwrap_or_default();
+    let network_settings = if is_rootless() {
+        NetworkDetails {
+            sandbox_key: String::new(),
+            ip_address: String::new(),
+            bridge_veth: String::new(),
+            container_veth: String::new(),
+        }
+    } else {
+        NetworkDetails {
+            sandbox_key: format!("{}/ns-{}", ROCKER_NETNS_PATH, container_id),
+            ip_address: fetch_ip_address(container_id)?.unwrap_or_default(),
+            bridge_veth: format!("br-veth-{}", &container_id[0..6]),
+            container_veth: format!("ns-veth-{}", &container_id[0..6]),
+        }
+    };
+
+    let mut args = command.clone();
+    let path = if args.is_empty() {
+        String::new()
+    } else {
+        args.remove(0)
+    };
+
+    Ok(ContainerDetails {
+        id: container_id.to_string(),
+        created: created,
+        path: path,
+        args: args,
+        state: state,
+        image: image_hash,
+        log_path: log_path(container_id),
+        hostname_path: format!("{}/hostname", container_path),
+        hosts_path: format!("{}/hosts", container_path),
+        resolv_conf_path: format!("{}/resolv.conf", container_path),
+        restart_count: restart_count,
+        config: ConfigDetails {
+            hostname: hostname,
+            user: user,
+            env: env,
+            cmd: command,
+            image: image_name,
+            working_dir: working_dir,
+        },
+        host_config: host_config,
+        graph_driver: GraphDriverDetails {
+            name: "overlay".to_string(),
+            upper_dir: format!("{}/fs/upperdir", container_path),
+            work_dir: format!("{}/fs/workdir", container_path),
+            merged_dir: format!("{}/fs/mnt", container_path),
+        },
+        mounts: mounts,
+        network_settings: network_settings,
+        cgroup_paths: fetch_cgroup_paths(container_id),
+    })
+}
+
+fn inspect_image(image_hash: &str, name_and_tag: &str) -> Result<ImageDetails> {
+    Ok(ImageDetails {
+        id: image_hash.to_string(),
+        repo_tags: vec![name_and_tag.to_string()],
+        config: load_image_config(image_hash)?,
+        layers: fetch_image_layers(image_hash)?,
+    })
+}
+
+// A small subset of Go templates, which is what `docker inspect --format`
+// takes: "{{.Field.Field}}", "{{json .Field}}" and "{{.}}".
+enum TemplatePart {
+    Text(String),
+    Field { path: Vec<String>, json: bool },
+}
+
+fn parse_template(format: &str) -> Result<Vec<TemplatePart>> {
+    let mut parts = Vec::new();
+    let mut rest = format;
+    while let Some(start) = rest.find("{{") {
+        if start > 0 {
+            parts.push(TemplatePart::Text(rest[..start].to_string()));
+        }
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => return Err(anyhow!("Unclosed action in template: {}", format)),
+        };
+        parts.push(parse_action(rest[start + 2..end].trim())?);
+        rest = &rest[end + 2..];
+    }
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Text(rest.to_string()));
+    }
+    Ok(parts)
+}
+
+fn parse_action(action: &str) -> Result<TemplatePart> {
+    let words: Vec<&str> = action.split_whitespace().collect();
+    let (field, json) = match words.as_slice() {
+        [field] => (*field, false),
+        ["json", field] => (*field, true),
+        _ => return Err(anyhow!("Unsupported template action: {{{{{}}}}}", action)),
+    };
+    if !field.starts_with('.') {
+        return Err(anyhow!("Unsupported template action: {{{{{}}}}}", action));
+    }
+    let path = field[1..]
+        .split('.')
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect();
+    Ok(TemplatePart::Field {
+        path: path,
+        json: json,
+    })
+}
+
+fn render_template(template: &[TemplatePart], document: &Value) -> Result<String> {
+    let mut output = String::new();
+    for part in template {
+        match part {
+            TemplatePart::Text(text) => output.push_str(text),
+            TemplatePart::Field { path, json } => {
+                let mut value = document;
+                for name in path {
+                    value = match value.get(name) {
+                        Some(value) => value,
+                        None => return Err(anyhow!("Can't evaluate field {}", name)),
+                    };
+                }
+                if *json {
+                    output.push_str(&serde_json::to_string(value)?);
+                } else {
+                    output.push_str(&format_value(value));
+                }
+            }
+        }
+    }
+    Ok(output)
+}
+
+// Prints values the way Go's text/template does: strings without quotes,
+// arrays as "[a b]" and objects as "map[key:value]".
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Null => "<nil>".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Array(values) => format!(
+            "[{}]",
+            values
+                .iter()
+                .map(format_value)
+                .collect::<Vec<String>>()
+                .join(" ")
+        ),
+        Value::Object(map) => format!(
+            "map[{}]",
+            map.iter()
+                .map(|(key, value)| format!("{}:{}", key, format_value(value)))
+                .collect::<Vec<String>>()
+                .join(" ")
+        ),
+        _ => value.to_string(),
+    }
+}
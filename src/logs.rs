@@ -0,0 +1,236 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    os::unix::io::RawFd,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, LocalResult, SecondsFormat, TimeZone, Utc};
+use nix::unistd::read;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    attach::AttachServer,
+    container::{fetch_container_state, ContainerState},
+    tty::write_all,
+    ROCKER_CONTAINERS_PATH,
+};
+
+// One line of the container's output, in the same format as Docker's
+// json-file logging driver.
+#[derive(Serialize, Deserialize)]
+struct LogEntry {
+    log: String,
+    stream: String,
+    time: String,
+}
+
+pub struct LogWriter {
+    file: Mutex<File>,
+}
+
+impl LogWriter {
+    pub fn open(container_id: &str) -> Result<Arc<Self>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_path(container_id))?;
+        Ok(Arc::new(LogWriter {
+            file: Mutex::new(file),
+        }))
+    }
+
+    fn write(&self, stream: &str, line: &[u8]) -> Result<()> {
+        let entry = LogEntry {
+            log: String::from_utf8_lossy(line).to_string(),
+            stream: stream.to_string(),
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Nanos, true),
+        };
+        let mut file = self.file.lock().unwrap();
+        writeln!(file, "{}", serde_json::to_string(&entry)?)?;
+        Ok(())
+    }
+}
+
+pub fn log_path(container_id: &str) -> String {
+    format!(
+        "{}/{}/{}-json.log",
+        *ROCKER_CONTAINERS_PATH, container_id, container_id
+    )
+}
+
+// Copies everything read from `src` to `dst`, the attached clients and, line
+// by line, to the log until `src` reaches EOF.
+pub fn copy_output(
+    src: RawFd,
+    dst: RawFd,
+    stream: &'static str,
+    log: Option<Arc<LogWriter>>,
+    attach: Option<Arc<AttachServer>>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut buf = [0u8; 4096];
+        let mut line = Vec::new();
+        loop {
+            let n = match read(src, &mut buf) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            let _ = write_all(dst, &buf[..n]);
+            if let Some(attach) = &attach {
+                attach.broadcast(stream, &buf[..n]);
+            }
+
+            if let Some(log) = &log {
+                for b in &buf[..n] {
+                    line.push(*b);
+                    if *b == b'\n' {
+                        let _ = log.write(stream, &line);
+                        line.clear();
+                    }
+                }
+            }
+        }
+
+        if let Some(log) = &log {
+            if !line.is_empty() {
+                let _ = log.write(stream, &line);
+            }
+        }
+    })
+}
+
+pub fn print_logs(
+    container_id: &str,
+    follow: bool,
+    tail: Option<usize>,
+    since: Option<String>,
+    timestamps: bool,
+) -> Result<()> {
+    if fetch_container_state(container_id)?.is_none() {
+        println!("container not found: {}", &container_id);
+        return Ok(());
+    }
+
+    let since = match since {
+        Some(since) => Some(parse_since(&since)?),
+        None => None,
+    };
+
+    let file = File::open(log_path(container_id))
+        .with_context(|| format!("No logs found for container: {}", container_id))?;
+    let mut reader = BufReader::new(file);
+
+    // A partially written line stays in `line` until it is complete.
+    let mut entries = Vec::new();
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        if !line.ends_with('\n') {
+            break;
+        }
+        entries.push(serde_json::from_str::<LogEntry>(&line)?);
+        line.clear();
+    }
+    let entries: Vec<LogEntry> = entries
+        .into_iter()
+        .filter(|entry| is_after(entry, since))
+        .collect();
+    let skip = match tail {
+        Some(tail) => entries.len().saturating_sub(tail),
+        None => 0,
+    };
+    for entry in entries.iter().skip(skip) {
+        print_entry(entry, timestamps)?;
+    }
+
+    if !follow {
+        return Ok(());
+    }
+
+    loop {
+        // Check the state before reading so that nothing written between
+        // the last read and the exit of the container is missed.
+        let is_running = matches!(
+            fetch_container_state(container_id)?,
+            Some(ContainerState::Running)
+                | Some(ContainerState::Paused)
+                | Some(ContainerState::Restarting)
+        );
+
+        while reader.read_line(&mut line)? > 0 {
+            if !line.ends_with('\n') {
+                break;
+            }
+            let entry = serde_json::from_str::<LogEntry>(&line)?;
+            if is_after(&entry, since) {
+                print_entry(&entry, timestamps)?;
+            }
+            line.clear();
+        }
+
+        if !is_running {
+            return Ok(());
+        }
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+fn print_entry(entry: &LogEntry, timestamps: bool) -> Result<()> {
+    let text = if timestamps {
+        format!("{} {}", entry.time, entry.log)
+    } else {
+        entry.log.clone()
+    };
+
+    if entry.stream == "stderr" {
+        let mut stderr = io::stderr();
+        stderr.write_all(text.as_bytes())?;
+        stderr.flush()?;
+    } else {
+        let mut stdout = io::stdout();
+        stdout.write_all(text.as_bytes())?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+fn is_after(entry: &LogEntry, since: Option<DateTime<Utc>>) -> bool {
+    match since {
+        Some(since) => match DateTime::parse_from_rfc3339(&entry.time) {
+            Ok(time) => time >= since,
+            Err(_) => true,
+        },
+        None => true,
+    }
+}
+
+// Accepts an RFC 3339 timestamp, a Unix timestamp, or a duration relative
+// to now such as "42s", "10m" or "1h30m".
+fn parse_since(since: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(since) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(secs) = since.parse::<i64>() {
+        if let LocalResult::Single(time) = Utc.timestamp_opt(secs, 0) {
+            return Ok(time);
+        }
+    }
+
+    let re = Regex::new(r"^(\d+h)?(\d+m)?(\d+s)?$").unwrap();
+    let caps = re
+        .captures(since)
+        .filter(|_| !since.is_empty())
+        .ok_or_else(|| anyhow!("Invalid value for --since: {}", since))?;
+    let mut secs = 0;
+    for (i, unit_secs) in [3600, 60, 1].iter().enumerate() {
+        if let Some(m) = caps.get(i + 1) {
+            let value: i64 = m.as_str()[..m.as_str().len() - 1].parse()?;
+            secs += value * unit_secs;
+        }
+    }
+    Ok(Utc::now() - chrono::Duration::seconds(secs))
+}
@@ -1,26 +1,64 @@
-use anyhow::{anyhow, Context, Result};
-use clap::Clap;
-use container::{exec_command_in_container, print_running_containers, run_container};
+use anyhow::{Context, Result};
+use attach::{attach_container, parse_detach_keys};
+use capabilities::{default_capabilities, resolve_capabilities};
+use clap::{AppSettings, Clap};
+use container::{
+    exec_command_in_container, kill_container, parse_env_options, pause_container,
+    print_containers, remove_container, run_container, stop_container, unpause_container,
+    wait_containers, ExecOptions, RunOptions,
+};
+use cp::copy_files;
+use etc_files::{parse_add_host_options, parse_dns_options, EtcFilesOptions};
 use image::{delete_image, print_available_images};
+use inspect::inspect;
+use logs::print_logs;
+use mount::{parse_tmpfs_options, parse_volume_options};
 use network::{is_network_bridge_up, setup_network_bridge};
+use once_cell::sync::Lazy;
+use restart::RestartPolicy;
+use rootless::{data_path, is_rootless};
+use seccomp::parse_security_options;
+use stats::print_stats;
 use std::fs::{self};
+use top::print_container_processes;
+use volume::{create_volume, inspect_volumes, print_volumes, remove_volumes};
 
-const ROCKER_TMP_PATH: &str = "/var/lib/rocker/tmp";
-const ROCKER_IMAGES_PATH: &str = "/var/lib/rocker/images";
-const ROCKER_DB_PATH: &str = "/var/lib/rocker/db";
-const ROCKER_CONTAINERS_PATH: &str = "/var/run/rocker/containers";
+static ROCKER_TMP_PATH: Lazy<String> = Lazy::new(|| data_path("/var/lib/rocker/tmp", "tmp"));
+static ROCKER_IMAGES_PATH: Lazy<String> =
+    Lazy::new(|| data_path("/var/lib/rocker/images", "images"));
+static ROCKER_DB_PATH: Lazy<String> = Lazy::new(|| data_path("/var/lib/rocker/db", "db"));
+static ROCKER_VOLUMES_PATH: Lazy<String> =
+    Lazy::new(|| data_path("/var/lib/rocker/volumes", "volumes"));
+static ROCKER_CONTAINERS_PATH: Lazy<String> =
+    Lazy::new(|| data_path("/var/run/rocker/containers", "containers"));
 const ROCKER_NETNS_PATH: &str = "/run/netns";
 const ROCKER_BRIDGE_NAME: &str = "rocker0";
 const ROCKER_NETWORK_ADDRESS: &str = "172.28.0.0/16";
 const ROCKER_BRIDGE_ADDRESS: &str = "172.28.0.1";
 
+mod attach;
+mod capabilities;
 mod cgroup;
 mod container;
+mod cp;
 mod db;
 mod dbus_systemd;
+mod etc_files;
 mod fork;
 mod image;
+mod inspect;
+mod logs;
+mod mount;
 mod network;
+mod restart;
+mod rootless;
+mod seccomp;
+mod stats;
+mod syscalls;
+mod top;
+mod tty;
+mod user;
+mod volume;
 
 #[derive(Clap)]
 struct Opts {
@@ -31,13 +69,27 @@ struct Opts {
 #[derive(Clap)]
 enum SubCommand {
     Run(Run),
-    Ps,
+    Ps(Ps),
     Exec(Exec),
+    Attach(Attach),
     Images,
     Rmi(Rmi),
+    Stop(Stop),
+    Kill(Kill),
+    Pause(Pause),
+    Unpause(Unpause),
+    Stats(Stats),
+    Top(Top),
+    Inspect(Inspect),
+    Cp(Cp),
+    Wait(Wait),
+    Rm(Rm),
+    Logs(Logs),
+    Volume(Volume),
 }
 
 #[derive(Clap)]
+#[clap(setting = AppSettings::TrailingVarArg)]
 struct Run {
     #[clap(short, long)]
     mem: Option<String>,
@@ -49,8 +101,178 @@ struct Run {
     username: Option<String>,
     #[clap(short, long)]
     password: Option<String>,
+    #[clap(short, long)]
+    detach: bool,
+    #[clap(long)]
+    rm: bool,
+    #[clap(short, long)]
+    interactive: bool,
+    #[clap(short, long)]
+    tty: bool,
+    #[clap(short, long, number_of_values = 1)]
+    env: Vec<String>,
+    #[clap(long, number_of_values = 1)]
+    env_file: Vec<String>,
+    #[clap(short, long, number_of_values = 1)]
+    volume: Vec<String>,
+    #[clap(long, number_of_values = 1)]
+    cap_add: Vec<String>,
+    #[clap(long, number_of_values = 1)]
+    cap_drop: Vec<String>,
+    #[clap(long)]
+    privileged: bool,
+    #[clap(long, number_of_values = 1)]
+    security_opt: Vec<String>,
+    #[clap(long)]
+    read_only: bool,
+    #[clap(long, number_of_values = 1)]
+    tmpfs: Vec<String>,
+    // -u is taken by --username.
+    #[clap(long)]
+    user: Option<String>,
+    #[clap(short, long)]
+    workdir: Option<String>,
+    // -h is taken by --help.
+    #[clap(long)]
+    hostname: Option<String>,
+    #[clap(long, number_of_values = 1)]
+    dns: Vec<String>,
+    #[clap(long, number_of_values = 1)]
+    dns_search: Vec<String>,
+    #[clap(long, number_of_values = 1)]
+    add_host: Vec<String>,
+    #[clap(long, default_value = "no")]
+    restart: RestartPolicy,
     image_name: String,
-    command: String,
+    command: Vec<String>,
+}
+
+#[derive(Clap)]
+struct Stop {
+    #[clap(short, long, default_value = "10")]
+    time: u64,
+    container_id: String,
+}
+
+#[derive(Clap)]
+struct Pause {
+    container_id: String,
+}
+
+#[derive(Clap)]
+struct Unpause {
+    container_id: String,
+}
+
+#[derive(Clap)]
+struct Stats {
+    #[clap(long)]
+    no_stream: bool,
+    container_ids: Vec<String>,
+}
+
+#[derive(Clap)]
+#[clap(setting = AppSettings::TrailingVarArg, setting = AppSettings::AllowLeadingHyphen)]
+struct Top {
+    container_id: String,
+    ps_args: Vec<String>,
+}
+
+#[derive(Clap)]
+struct Attach {
+    // A comma separated sequence of keys, e.g. "ctrl-a,d".
+    #[clap(long, default_value = "ctrl-p,ctrl-q")]
+    detach_keys: String,
+    container_id: String,
+}
+
+#[derive(Clap)]
+struct Inspect {
+    // A Go-like template, e.g. "{{.State.Pid}}" or "{{json .Mounts}}".
+    #[clap(short, long)]
+    format: Option<String>,
+    #[clap(required = true)]
+    names: Vec<String>,
+}
+
+#[derive(Clap)]
+struct Cp {
+    // <container-id>:<path>, a host path or "-" to read a tar archive from
+    // stdin.
+    src: String,
+    // <container-id>:<path>, a host path or "-" to write a tar archive to
+    // stdout.
+    dst: String,
+}
+
+#[derive(Clap)]
+struct Wait {
+    #[clap(required = true)]
+    container_ids: Vec<String>,
+}
+
+#[derive(Clap)]
+struct Kill {
+    #[clap(short, long, default_value = "KILL")]
+    signal: String,
+    container_id: String,
+}
+
+#[derive(Clap)]
+struct Ps {
+    #[clap(short, long)]
+    all: bool,
+}
+
+#[derive(Clap)]
+struct Rm {
+    #[clap(short, long)]
+    force: bool,
+    container_id: String,
+}
+
+#[derive(Clap)]
+struct Logs {
+    #[clap(short, long)]
+    follow: bool,
+    #[clap(long)]
+    tail: Option<usize>,
+    #[clap(long)]
+    since: Option<String>,
+    #[clap(short, long)]
+    timestamps: bool,
+    container_id: String,
+}
+
+#[derive(Clap)]
+struct Volume {
+    #[clap(subcommand)]
+    subcmd: VolumeCommand,
+}
+
+#[derive(Clap)]
+enum VolumeCommand {
+    Create(VolumeCreate),
+    Ls,
+    Inspect(VolumeInspect),
+    Rm(VolumeRm),
+}
+
+#[derive(Clap)]
+struct VolumeCreate {
+    name: Option<String>,
+}
+
+#[derive(Clap)]
+struct VolumeInspect {
+    #[clap(required = true)]
+    names: Vec<String>,
+}
+
+#[derive(Clap)]
+struct VolumeRm {
+    #[clap(required = true)]
+    names: Vec<String>,
 }
 
 #[derive(Clap)]
@@ -59,18 +281,34 @@ struct Rmi {
 }
 
 #[derive(Clap)]
+#[clap(setting = AppSettings::TrailingVarArg)]
 struct Exec {
+    #[clap(short, long)]
+    interactive: bool,
+    #[clap(short, long)]
+    tty: bool,
+    #[clap(short, long, number_of_values = 1)]
+    env: Vec<String>,
+    #[clap(long, number_of_values = 1)]
+    env_file: Vec<String>,
+    #[clap(long, number_of_values = 1)]
+    cap_add: Vec<String>,
+    #[clap(long, number_of_values = 1)]
+    cap_drop: Vec<String>,
+    #[clap(long)]
+    privileged: bool,
+    #[clap(short, long)]
+    user: Option<String>,
+    #[clap(short, long)]
+    workdir: Option<String>,
     container_id: String,
-    command: String,
+    #[clap(required = true)]
+    command: Vec<String>,
 }
 
 fn main() -> Result<()> {
     let opts: Opts = Opts::parse();
 
-    if !nix::unistd::getuid().is_root() {
-        return Err(anyhow!("You need root privileges to run this program."));
-    };
-
     init_dirs()?;
 
     match opts.subcmd {
@@ -78,33 +316,113 @@ fn main() -> Result<()> {
             let rt = tokio::runtime::Runtime::new()?;
 
             let task = async {
-                if !is_network_bridge_up().await? {
+                let options = RunOptions {
+                    mem: r.mem,
+                    cpus: r.cpus,
+                    pids_limit: r.pids_limit,
+                    detach: r.detach,
+                    auto_remove: r.rm,
+                    env: parse_env_options(r.env, r.env_file)?,
+                    tty: r.tty,
+                    interactive: r.interactive,
+                    mounts: parse_volume_options(r.volume)?,
+                    capabilities: resolve_capabilities(
+                        &default_capabilities(),
+                        &r.cap_add,
+                        &r.cap_drop,
+                        r.privileged,
+                    )?,
+                    seccomp_profile: parse_security_options(&r.security_opt, r.privileged)?,
+                    read_only: r.read_only,
+                    tmpfs: parse_tmpfs_options(r.tmpfs)?,
+                    user: r.user,
+                    workdir: r.workdir,
+                    hostname: r.hostname,
+                    etc_files: EtcFilesOptions {
+                        dns: parse_dns_options(r.dns)?,
+                        dns_search: r.dns_search,
+                        extra_hosts: parse_add_host_options(r.add_host)?,
+                    },
+                    restart_policy: r.restart,
+                };
+                // Creating a bridge needs real root, so rootless containers
+                // aren't given a network of their own.
+                if is_rootless() {
+                    eprintln!("WARNING: Rootless containers share the host's network");
+                } else if !is_network_bridge_up().await? {
                     setup_network_bridge().await?
                 };
-                run_container(
-                    r.mem,
-                    r.cpus,
-                    r.pids_limit,
-                    r.image_name,
-                    r.username,
-                    r.password,
-                    r.command,
-                )
-                .await
+                run_container(r.image_name, r.username, r.password, r.command, options).await
             };
-            rt.block_on(task)?
+            // Exits with the container's exit code unless it's detached.
+            if let Some(exit_code) = rt.block_on(task)? {
+                drop(rt);
+                std::process::exit(exit_code);
+            }
         }
-        SubCommand::Ps => print_running_containers()?,
+        SubCommand::Ps(p) => print_containers(p.all)?,
         SubCommand::Images => print_available_images()?,
         SubCommand::Rmi(r) => delete_image(&r.image_hash)?,
-        SubCommand::Exec(exec) => exec_command_in_container(&exec.container_id, &exec.command)?,
+        SubCommand::Exec(exec) => {
+            let options = ExecOptions {
+                env: parse_env_options(exec.env, exec.env_file)?,
+                tty: exec.tty,
+                interactive: exec.interactive,
+                cap_add: exec.cap_add,
+                cap_drop: exec.cap_drop,
+                privileged: exec.privileged,
+                user: exec.user,
+                workdir: exec.workdir,
+            };
+            exec_command_in_container(&exec.container_id, &exec.command, options)?
+        }
+        SubCommand::Attach(a) => {
+            let detach_keys = parse_detach_keys(&a.detach_keys)?;
+            // Exits with the container's exit code unless detached.
+            if let Some(exit_code) = attach_container(&a.container_id, &detach_keys)? {
+                std::process::exit(exit_code);
+            }
+        }
+        SubCommand::Stop(s) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(stop_container(&s.container_id, s.time))?
+        }
+        SubCommand::Kill(k) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(kill_container(&k.container_id, &k.signal))?
+        }
+        SubCommand::Pause(p) => pause_container(&p.container_id)?,
+        SubCommand::Unpause(u) => unpause_container(&u.container_id)?,
+        SubCommand::Stats(s) => print_stats(&s.container_ids, s.no_stream)?,
+        SubCommand::Top(t) => print_container_processes(&t.container_id, &t.ps_args)?,
+        SubCommand::Inspect(i) => inspect(&i.names, i.format.as_deref())?,
+        SubCommand::Wait(w) => wait_containers(&w.container_ids)?,
+        SubCommand::Cp(c) => copy_files(&c.src, &c.dst)?,
+        SubCommand::Logs(l) => {
+            print_logs(&l.container_id, l.follow, l.tail, l.since, l.timestamps)?
+        }
+        SubCommand::Volume(v) => match v.subcmd {
+            VolumeCommand::Create(c) => create_volume(c.name)?,
+            VolumeCommand::Ls => print_volumes()?,
+            VolumeCommand::Inspect(i) => inspect_volumes(&i.names)?,
+            VolumeCommand::Rm(r) => remove_volumes(&r.names)?,
+        },
+        SubCommand::Rm(r) => {
+            let rt = tokio::runtime::Runtime::new()?;
+            rt.block_on(remove_container(&r.container_id, r.force))?
+        }
     };
 
     Ok(())
 }
 
 fn init_dirs() -> Result<()> {
-    let dirs = [ROCKER_TMP_PATH, ROCKER_IMAGES_PATH, ROCKER_CONTAINERS_PATH];
+    let dirs = [
+        &*ROCKER_TMP_PATH,
+        &*ROCKER_IMAGES_PATH,
+        &*ROCKER_CONTAINERS_PATH,
+        &*ROCKER_VOLUMES_PATH,
+    ];
 
     for path in dirs.iter() {
         fs::create_dir_all(path)
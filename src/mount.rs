@@ -0,0 +1,428 @@
+use std::{
+    ffi::OsString,
+    fs::{self, create_dir_all, OpenOptions},
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use nix::{
+    mount::{mount, MsFlags},
+    sys::statvfs::{statvfs, FsFlags},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::volume::{ensure_volume, is_valid_volume_name};
+
+// The same limit as the kernel's.
+const MAX_SYMLINKS: usize = 40;
+
+// A host path or a named volume mounted into the container with `-v`.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Mount {
+    // Name of the volume if this is a named volume.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub source: String,
+    pub destination: String,
+    pub read_only: bool,
+}
+
+// A tmpfs mounted into the container with `--tmpfs`. `options` are the ones
+// given after the path, e.g. "size=64m,mode=1777".
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "PascalCase")]
+pub struct Tmpfs {
+    pub destination: String,
+    pub options: String,
+}
+
+// Parses "/host/path:/container/path[:ro|rw]" or "name:/container/path[:ro|rw]".
+// The host path or the volume is created if it doesn't exist, the same way
+// Docker does.
+pub fn parse_volume_option(volume: &str) -> Result<Mount> {
+    let parts: Vec<&str> = volume.split(':').collect();
+    let (source, destination, mode) = match parts.as_slice() {
+        [source, destination] => (*source, *destination, "rw"),
+        [source, destination, mode] => (*source, *destination, *mode),
+        _ => return Err(anyhow!("Invalid volume specification: {}", volume)),
+    };
+
+    let read_only = match mode {
+        "ro" => true,
+        "rw" => false,
+        _ => return Err(anyhow!("Invalid mode for volume {}: {}", volume, mode)),
+    };
+    if !is_valid_container_path(destination) {
+        return Err(anyhow!("Invalid container path: {}", destination));
+    }
+
+    if !source.starts_with('/') {
+        if !is_valid_volume_name(source) {
+            return Err(anyhow!(
+                "Host path must be absolute or a valid volume name: {}",
+                source
+            ));
+        }
+        return Ok(Mount {
+            name: Some(source.to_string()),
+            source: ensure_volume(source)?,
+            destination: destination.to_string(),
+            read_only: read_only,
+        });
+    }
+
+    // A missing host path is created as a directory. An existing file is
+    // mounted as it is.
+    if !Path::new(source).exists() {
+        create_dir_all(source)
+            .with_context(|| format!("Failed to create directory: {}", source))?;
+    }
+    let source = fs::canonicalize(source)?;
+
+    Ok(Mount {
+        name: None,
+        source: source.to_string_lossy().to_string(),
+        destination: destination.to_string(),
+        read_only: read_only,
+    })
+}
+
+// An absolute path other than / without "..", which could lead out of the
+// container's rootfs.
+fn is_valid_container_path(path: &str) -> bool {
+    path.starts_with('/')
+        && path != "/"
+        && !Path::new(path)
+            .components()
+            .any(|c| c == Component::ParentDir)
+}
+
+// A mount on a subdirectory of another mount has to come after it.
+fn mount_depth(destination: &str) -> usize {
+    destination.trim_end_matches('/').matches('/').count()
+}
+
+pub fn parse_volume_options(volumes: Vec<String>) -> Result<Vec<Mount>> {
+    let mut mounts = volumes
+        .iter()
+        .map(|v| parse_volume_option(v))
+        .collect::<Result<Vec<Mount>>>()?;
+    mounts.sort_by_key(|m| mount_depth(&m.destination));
+    Ok(mounts)
+}
+
+enum ContainerMount<'a> {
+    Bind(&'a Mount),
+    Tmpfs(&'a Tmpfs),
+}
+
+// Mounts the `-v` and `--tmpfs` mounts together, so that one on a
+// subdirectory of another comes after it whichever kinds they are. Must be
+// called in the container's mount namespace before pivot_root.
+pub fn mount_bind_and_tmpfs_mounts(rootfs: &str, mounts: &[Mount], tmpfs: &[Tmpfs]) -> Result<()> {
+    let mut all: Vec<ContainerMount> = mounts
+        .iter()
+        .map(ContainerMount::Bind)
+        .chain(tmpfs.iter().map(ContainerMount::Tmpfs))
+        .collect();
+    all.sort_by_key(|m| match m {
+        ContainerMount::Bind(m) => mount_depth(&m.destination),
+        ContainerMount::Tmpfs(m) => mount_depth(&m.destination),
+    });
+
+    for m in all {
+        match m {
+            ContainerMount::Bind(m) => mount_bind_mount(rootfs, m)?,
+            ContainerMount::Tmpfs(m) => mount_tmpfs_mount(rootfs, m)?,
+        }
+    }
+    Ok(())
+}
+
+// Must be called in the container's mount namespace before pivot_root.
+pub fn mount_bind_mounts(rootfs: &str, mounts: &[Mount]) -> Result<()> {
+    for m in mounts {
+        mount_bind_mount(rootfs, m)?;
+    }
+    Ok(())
+}
+
+fn mount_bind_mount(rootfs: &str, m: &Mount) -> Result<()> {
+    let target = create_mount_point(rootfs, &m.destination, Path::new(&m.source).is_dir())?;
+
+    mount::<str, Path, str, str>(
+        Some(m.source.as_str()),
+        &target,
+        None,
+        MsFlags::MS_BIND | MsFlags::MS_REC,
+        None,
+    )
+    .with_context(|| format!("Failed to mount {} on {}", m.source, m.destination))?;
+
+    // MS_RDONLY is ignored when a bind mount is created, so it has to be
+    // applied by remounting.
+    if m.read_only {
+        mount::<str, Path, str, str>(
+            None,
+            &target,
+            None,
+            MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY | mount_flags(&target)?,
+            None,
+        )?;
+    }
+    Ok(())
+}
+
+// Creates the mount point for `destination` and returns its path on the host.
+// Symlinks are resolved in `rootfs`, and one at the mount point itself is
+// replaced, so that nothing is created or mounted outside of it. The mount
+// point has to be of the same type (directory or file) as the source.
+fn create_mount_point(rootfs: &str, destination: &str, is_dir: bool) -> Result<PathBuf> {
+    let target = resolve_in_root(Path::new(rootfs), destination, false)?;
+    if !target.starts_with(rootfs) || target == Path::new(rootfs) {
+        return Err(anyhow!("Invalid container path: {}", destination));
+    }
+
+    if fs::symlink_metadata(&target).map_or(false, |m| m.file_type().is_symlink()) {
+        fs::remove_file(&target)?;
+    }
+    if is_dir {
+        create_dir_all(&target)?;
+    } else {
+        if let Some(parent) = target.parent() {
+            create_dir_all(parent)?;
+        }
+        OpenOptions::new().create(true).write(true).open(&target)?;
+    }
+    Ok(target)
+}
+
+// Resolves `path` in the container's filesystem at `root`. Symlinks are
+// resolved as if `root` was /, so that they can't lead out of the container.
+// The last component is only resolved with `follow_last`.
+pub fn resolve_in_root(root: &Path, path: &str, follow_last: bool) -> Result<PathBuf> {
+    let mut resolved = PathBuf::new();
+    // The components left to resolve, in reverse order.
+    let mut rest = Vec::new();
+    push_components(&mut rest, Path::new(path));
+    let mut symlinks = 0;
+
+    while let Some(name) = rest.pop() {
+        if name == ".." {
+            resolved.pop();
+            continue;
+        }
+        let candidate = resolved.join(&name);
+        let is_symlink = fs::symlink_metadata(root.join(&candidate))
+            .map_or(false, |m| m.file_type().is_symlink());
+        if !is_symlink || (rest.is_empty() && !follow_last) {
+            resolved = candidate;
+            continue;
+        }
+
+        symlinks += 1;
+        if symlinks > MAX_SYMLINKS {
+            return Err(anyhow!("Too many levels of symbolic links: {}", path));
+        }
+        let target = fs::read_link(root.join(&candidate))?;
+        if target.is_absolute() {
+            resolved = PathBuf::new();
+        }
+        push_components(&mut rest, &target);
+    }
+    Ok(root.join(resolved))
+}
+
+fn push_components(rest: &mut Vec<OsString>, path: &Path) {
+    for component in path.components().rev() {
+        match component {
+            Component::Normal(name) => rest.push(name.to_os_string()),
+            Component::ParentDir => rest.push(OsString::from("..")),
+            _ => {}
+        }
+    }
+}
+
+// Parses "/container/path[:options]".
+pub fn parse_tmpfs_option(tmpfs: &str) -> Result<Tmpfs> {
+    let (destination, options) = match tmpfs.find(':') {
+        Some(i) => (&tmpfs[..i], &tmpfs[i + 1..]),
+        None => (tmpfs, ""),
+    };
+    if !is_valid_container_path(destination) {
+        return Err(anyhow!("Invalid container path: {}", destination));
+    }
+    tmpfs_mount_flags(options).with_context(|| format!("Invalid tmpfs options: {}", tmpfs))?;
+
+    Ok(Tmpfs {
+        destination: destination.to_string(),
+        options: options.to_string(),
+    })
+}
+
+pub fn parse_tmpfs_options(tmpfs: Vec<String>) -> Result<Vec<Tmpfs>> {
+    let mut mounts = tmpfs
+        .iter()
+        .map(|t| parse_tmpfs_option(t))
+        .collect::<Result<Vec<Tmpfs>>>()?;
+    mounts.sort_by_key(|m| mount_depth(&m.destination));
+    Ok(mounts)
+}
+
+// Splits the options into mount flags and the data passed to tmpfs. Like
+// Docker, a tmpfs is noexec, nosuid and nodev unless told otherwise.
+fn tmpfs_mount_flags(options: &str) -> Result<(MsFlags, String)> {
+    let mut flags = MsFlags::MS_NOEXEC | MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+    let mut data = Vec::new();
+    for option in options.split(',').filter(|o| !o.is_empty()) {
+        match option {
+            "ro" => flags.insert(MsFlags::MS_RDONLY),
+            "rw" => flags.remove(MsFlags::MS_RDONLY),
+            "noexec" => flags.insert(MsFlags::MS_NOEXEC),
+            "exec" => flags.remove(MsFlags::MS_NOEXEC),
+            "nosuid" => flags.insert(MsFlags::MS_NOSUID),
+            "suid" => flags.remove(MsFlags::MS_NOSUID),
+            "nodev" => flags.insert(MsFlags::MS_NODEV),
+            "dev" => flags.remove(MsFlags::MS_NODEV),
+            _ => {
+                let key = option.split('=').next().unwrap_or_default();
+                if !["size", "mode", "uid", "gid", "nr_inodes", "nr_blocks"].contains(&key)
+                    || !option.contains('=')
+                {
+                    return Err(anyhow!("Unknown option: {}", option));
+                }
+                data.push(option);
+            }
+        }
+    }
+    Ok((flags, data.join(",")))
+}
+
+fn mount_tmpfs_mount(rootfs: &str, m: &Tmpfs) -> Result<()> {
+    let target = create_mount_point(rootfs, &m.destination, true)?;
+    let (flags, data) = tmpfs_mount_flags(&m.options)?;
+    mount::<str, Path, str, str>(
+        Some("tmpfs"),
+        &target,
+        Some("tmpfs"),
+        flags,
+        Some(data.as_str()),
+    )
+    .with_context(|| format!("Failed to mount tmpfs on {}", m.destination))?;
+    Ok(())
+}
+
+// Makes the container's root filesystem read-only with `--read-only`. Must be
+// called once every mount point in it has been created.
+pub fn remount_root_read_only(rootfs: &str) -> Result<()> {
+    mount::<str, str, str, str>(
+        None,
+        rootfs,
+        None,
+        MsFlags::MS_BIND
+            | MsFlags::MS_REMOUNT
+            | MsFlags::MS_RDONLY
+            | mount_flags(Path::new(rootfs))?,
+        None,
+    )
+    .with_context(|| "Failed to make the root filesystem read-only")?;
+    Ok(())
+}
+
+// The flags of the mount at `path`, which a remount has to keep. Otherwise
+// they are cleared, and the kernel refuses that for the flags it locks, like
+// those of mounts from outside the user namespace in rootless mode.
+fn mount_flags(path: &Path) -> Result<MsFlags> {
+    let st_flags = statvfs(path)
+        .with_context(|| format!("Failed to get mount flags of {}", path.display()))?
+        .flags();
+    let mut flags = MsFlags::empty();
+    for (st_flag, flag) in [
+        (FsFlags::ST_NOSUID, MsFlags::MS_NOSUID),
+        (FsFlags::ST_NODEV, MsFlags::MS_NODEV),
+        (FsFlags::ST_NOEXEC, MsFlags::MS_NOEXEC),
+        (FsFlags::ST_NOATIME, MsFlags::MS_NOATIME),
+        (FsFlags::ST_NODIRATIME, MsFlags::MS_NODIRATIME),
+        (FsFlags::ST_RELATIME, MsFlags::MS_RELATIME),
+    ]
+    .iter()
+    {
+        if st_flags.contains(*st_flag) {
+            flags.insert(*flag);
+        }
+    }
+    // A remount without any of these flags would turn on relatime.
+    if !st_flags.intersects(FsFlags::ST_NOATIME | FsFlags::ST_RELATIME) {
+        flags.insert(MsFlags::MS_STRICTATIME);
+    }
+    Ok(flags)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nix::sched::{unshare, CloneFlags};
+    use std::{process, thread};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rocker-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_bind_mount_file() {
+        let dir = test_dir("bind-mount-file");
+        let file = dir.join("file");
+        fs::write(&file, "from the host").unwrap();
+        let rootfs = dir.join("rootfs");
+        create_dir_all(rootfs.join("etc")).unwrap();
+
+        let m = parse_volume_option(&format!("{}:/etc/file:ro", file.display())).unwrap();
+        assert_eq!(m.source, file.to_string_lossy());
+        assert!(file.is_file());
+
+        // Namespaces belong to a thread, so the mounts don't leak out of it.
+        let (host, rootfs) = (dir.clone(), rootfs.to_string_lossy().to_string());
+        let mounted = thread::spawn(move || {
+            if unshare(CloneFlags::CLONE_NEWNS).is_err() {
+                // Not permitted without root.
+                return None;
+            }
+            mount::<str, str, str, str>(
+                None,
+                "/",
+                None,
+                MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                None,
+            )
+            .unwrap();
+            // The flags of the file's mount have to be kept when it's made
+            // read-only.
+            let flags = MsFlags::MS_BIND | MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+            mount::<Path, Path, str, str>(Some(&host), &host, None, flags, None).unwrap();
+            mount::<str, Path, str, str>(None, &host, None, flags | MsFlags::MS_REMOUNT, None)
+                .unwrap();
+
+            mount_bind_mounts(&rootfs, &[m]).unwrap();
+            let target = format!("{}/etc/file", rootfs);
+            Some((
+                fs::read_to_string(&target).unwrap(),
+                statvfs(target.as_str()).unwrap().flags(),
+            ))
+        })
+        .join()
+        .unwrap();
+
+        let target = dir.join("rootfs/etc/file");
+        assert!(fs::symlink_metadata(&target).unwrap().is_file());
+        if let Some((content, flags)) = mounted {
+            assert_eq!(content, "from the host");
+            let expected = FsFlags::ST_RDONLY | FsFlags::ST_NOSUID | FsFlags::ST_NODEV;
+            assert!(flags.contains(expected), "{:?}", flags);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    fs::OpenOptions,
+    fs::{self, OpenOptions},
     net::{IpAddr, Ipv4Addr},
     os::unix::prelude::IntoRawFd,
     process::exit,
@@ -32,10 +32,9 @@ use rtnetlink::{
 };
 
 use crate::{
-    db::{used_ip_addresses_key, veth_ip_addresses_key},
+    db::{open_db, used_ip_addresses_key, veth_ip_addresses_key},
     fork::fork_fn,
-    ROCKER_BRIDGE_ADDRESS, ROCKER_BRIDGE_NAME, ROCKER_DB_PATH, ROCKER_NETNS_PATH,
-    ROCKER_NETWORK_ADDRESS,
+    ROCKER_BRIDGE_ADDRESS, ROCKER_BRIDGE_NAME, ROCKER_NETNS_PATH, ROCKER_NETWORK_ADDRESS,
 };
 
 pub async fn is_network_bridge_up() -> Result<bool> {
@@ -137,7 +136,7 @@ pub async fn setup_veths(container_id: &String) -> Result<()> {
     )
     .await?;
 
-    let db = sled::open(ROCKER_DB_PATH).unwrap();
+    let db = open_db()?;
     let ip_addr = Arc::new(create_ip_address(&handle, &db)?);
 
     run_in_network_namespace(
@@ -338,6 +337,33 @@ pub fn run_in_network_namespace(
     )
 }
 
+// The IP address of the container's veth, if it has one.
+pub fn fetch_ip_address(container_id: &str) -> Result<Option<String>> {
+    let db = open_db()?;
+    let ip_addr = db.get(veth_ip_addresses_key(&format!(
+        "ns-veth-{}",
+        &container_id[0..6]
+    )))?;
+    Ok(match ip_addr {
+        Some(ip_addr) => Some(String::from_utf8(ip_addr.to_vec())?),
+        None => None,
+    })
+}
+
+// Bytes received and sent by the container, which are the other way around
+// on the bridge side of its veth pair. None if it has no veth.
+pub fn read_veth_stats(container_id: &str) -> Option<(u64, u64)> {
+    let stats_path = format!("/sys/class/net/br-veth-{}/statistics", &container_id[0..6]);
+    let read = |name: &str| -> Option<u64> {
+        fs::read_to_string(format!("{}/{}", stats_path, name))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    };
+    Some((read("tx_bytes")?, read("rx_bytes")?))
+}
+
 fn create_ip_address(_handle: &Handle, db: &sled::Db) -> Result<IpAddr> {
     let mut is_ok = false;
     let mut rand_nums = rand::thread_rng().gen::<[u8; 2]>();
@@ -0,0 +1,77 @@
+use std::{fmt, str::FromStr, time::Duration};
+
+use anyhow::{anyhow, Result};
+
+// The delay before a restart starts at 100ms and doubles every time, up to a
+// minute. It's reset once the container has been up for 10 seconds, like
+// Docker does.
+const INITIAL_RESTART_DELAY: Duration = Duration::from_millis(100);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+const RESET_RESTART_DELAY_AFTER: Duration = Duration::from_secs(10);
+
+// `--restart no|on-failure[:max-retries]|always|unless-stopped`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RestartPolicy {
+    No,
+    OnFailure(Option<u32>),
+    Always,
+    // The same as Always, since there's no daemon to restart containers
+    // after a reboot.
+    UnlessStopped,
+}
+
+impl RestartPolicy {
+    // A container which has been stopped with `rocker stop` or `rocker kill`
+    // is never restarted.
+    pub fn should_restart(&self, exit_code: i32, restart_count: u32) -> bool {
+        match self {
+            RestartPolicy::No => false,
+            RestartPolicy::OnFailure(max_retries) => {
+                exit_code != 0 && max_retries.map_or(true, |max| restart_count < max)
+            }
+            RestartPolicy::Always | RestartPolicy::UnlessStopped => true,
+        }
+    }
+}
+
+impl FromStr for RestartPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let max_retries = parts.next();
+        match (name, max_retries) {
+            ("no", None) => Ok(RestartPolicy::No),
+            ("always", None) => Ok(RestartPolicy::Always),
+            ("unless-stopped", None) => Ok(RestartPolicy::UnlessStopped),
+            ("on-failure", None) => Ok(RestartPolicy::OnFailure(None)),
+            ("on-failure", Some(max)) => match max.parse() {
+                Ok(max) => Ok(RestartPolicy::OnFailure(Some(max))),
+                Err(_) => Err(anyhow!("Invalid maximum retry count: {}", max)),
+            },
+            _ => Err(anyhow!("Invalid restart policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for RestartPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RestartPolicy::No => write!(f, "no"),
+            RestartPolicy::OnFailure(None) => write!(f, "on-failure"),
+            RestartPolicy::OnFailure(Some(max)) => write!(f, "on-failure:{}", max),
+            RestartPolicy::Always => write!(f, "always"),
+            RestartPolicy::UnlessStopped => write!(f, "unless-stopped"),
+        }
+    }
+}
+
+// Returns the delay before the next restart, given the previous one (None
+// for the first restart) and how long the container was up.
+pub fn next_restart_delay(previous: Option<Duration>, uptime: Duration) -> Duration {
+    match previous {
+        Some(delay) if uptime < RESET_RESTART_DELAY_AFTER => (delay * 2).min(MAX_RESTART_DELAY),
+        _ => INITIAL_RESTART_DELAY,
+    }
+}
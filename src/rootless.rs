@@ -0,0 +1,202 @@
+use std::{
+    env,
+    fs::{self, File},
+    os::unix::io::{AsRawFd, RawFd},
+    process::Command,
+};
+
+use anyhow::{anyhow, Context, Result};
+use nix::{
+    sched::{clone, setns, CloneFlags},
+    sys::{
+        signal::Signal,
+        wait::{waitpid, WaitStatus},
+    },
+    unistd::{close, getgid, getuid, pipe, read, write, Pid, User},
+};
+
+// Rocker runs in rootless mode when it is started by a user other than root.
+pub fn is_rootless() -> bool {
+    !getuid().is_root()
+}
+
+// In rootless mode, everything rocker stores is kept under
+// $XDG_DATA_HOME/rocker (~/.local/share/rocker by default) instead.
+pub fn data_path(root_path: &str, name: &str) -> String {
+    if !is_rootless() {
+        return root_path.to_string();
+    }
+
+    let data_home = match env::var("XDG_DATA_HOME") {
+        Ok(dir) if !dir.is_empty() => dir,
+        _ => format!("{}/.local/share", env::var("HOME").unwrap_or_default()),
+    };
+    format!("{}/rocker/{}", data_home, name)
+}
+
+// Maps root in the user namespace of `pid` to the calling user, and the
+// rest of the IDs to the user's subordinate IDs in /etc/subuid and
+// /etc/subgid if there are any. newuidmap/newgidmap are needed to map more
+// than the calling user's own IDs.
+pub fn write_id_mappings(pid: Pid) -> Result<()> {
+    let uid = getuid().as_raw();
+    let gid = getgid().as_raw();
+    let user_name = User::from_uid(getuid())?
+        .map(|user| user.name)
+        .unwrap_or_default();
+
+    let subuids = find_subordinate_ids("/etc/subuid", &user_name, uid)?;
+    let subgids = find_subordinate_ids("/etc/subgid", &user_name, uid)?;
+    match (subuids, subgids) {
+        (Some((uid_start, uid_count)), Some((gid_start, gid_count))) => {
+            run_id_map_helper("newuidmap", pid, uid, uid_start, uid_count)?;
+            run_id_map_helper("newgidmap", pid, gid, gid_start, gid_count)?;
+        }
+        _ => {
+            eprintln!(
+                "No subordinate IDs found for {} in /etc/subuid and /etc/subgid, only root is mapped in the container",
+                user_name
+            );
+            fs::write(format!("/proc/{}/uid_map", pid), format!("0 {} 1", uid))?;
+            // An unprivileged process can only write gid_map once setgroups()
+            // has been disabled.
+            fs::write(format!("/proc/{}/setgroups", pid), "deny")?;
+            fs::write(format!("/proc/{}/gid_map", pid), format!("0 {} 1", gid))?;
+        }
+    }
+    Ok(())
+}
+
+// Each line of /etc/subuid and /etc/subgid is "user:start:count", where
+// "user" is a name or a uid. Returns the first range of the user.
+fn find_subordinate_ids(path: &str, user_name: &str, uid: u32) -> Result<Option<(u32, u32)>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(_) => return Ok(None),
+    };
+
+    for line in content.lines() {
+        let fields: Vec<&str> = line.trim().split(':').collect();
+        if fields.len() != 3 {
+            continue;
+        }
+        if fields[0] == user_name || fields[0] == uid.to_string() {
+            return Ok(Some((fields[1].parse()?, fields[2].parse()?)));
+        }
+    }
+    Ok(None)
+}
+
+fn run_id_map_helper(helper: &str, pid: Pid, id: u32, start: u32, count: u32) -> Result<()> {
+    let status = Command::new(helper)
+        .args(&[
+            pid.to_string(),
+            "0".to_string(),
+            id.to_string(),
+            "1".to_string(),
+            "1".to_string(),
+            start.to_string(),
+            count.to_string(),
+        ])
+        .status()
+        .with_context(|| format!("Failed to run {} (is uidmap installed?)", helper))?;
+    if !status.success() {
+        return Err(anyhow!("{} failed: {}", helper, status));
+    }
+    Ok(())
+}
+
+// A process cloned into a new user namespace waits on `sync_pipe` (a pipe
+// created before clone) until the parent has written its ID mappings.
+pub fn wait_for_id_mappings(sync_pipe: (RawFd, RawFd)) -> Result<()> {
+    let (read_fd, write_fd) = sync_pipe;
+    close(write_fd)?;
+    let mut buf = [0u8; 1];
+    let n = read(read_fd, &mut buf)?;
+    close(read_fd)?;
+    if n == 0 {
+        return Err(anyhow!("Failed to set up the user namespace"));
+    }
+    Ok(())
+}
+
+// Called by the parent once the child has been cloned. The child gives up
+// if the mappings can't be written.
+pub fn finish_id_mappings(pid: Pid, sync_pipe: (RawFd, RawFd)) -> Result<()> {
+    let (read_fd, write_fd) = sync_pipe;
+    close(read_fd)?;
+    let res = write_id_mappings(pid);
+    if res.is_ok() {
+        write(write_fd, b"x")?;
+    }
+    close(write_fd)?;
+    res
+}
+
+// Runs `fun` as root of a new user namespace with the same ID mappings as the
+// containers. Files created by a container as a user other than root are
+// owned by a subordinate ID, which the calling user can't remove otherwise.
+pub fn run_in_user_namespace(mut fun: impl FnMut() -> Result<()>) -> Result<()> {
+    const STACK_SIZE: usize = 1024 * 1024;
+    let mut stack = Box::new([0; STACK_SIZE]);
+    let sync_pipe = pipe()?;
+
+    let cb = Box::new(|| {
+        wait_for_id_mappings(sync_pipe).unwrap();
+        match fun() {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            }
+        }
+    });
+    let pid = clone(
+        cb,
+        &mut *stack,
+        CloneFlags::CLONE_NEWUSER,
+        Some(Signal::SIGCHLD as i32),
+    )?;
+    finish_id_mappings(pid, sync_pipe)?;
+
+    match waitpid(pid, None)? {
+        WaitStatus::Exited(_, 0) => Ok(()),
+        status => Err(anyhow!("Failed in the user namespace: {:?}", status)),
+    }
+}
+
+// Runs `fun` as root of the user namespace of the container whose process is
+// `pid`. Unlike a new user namespace, it can reach into the container's
+// processes, e.g. through /proc/<pid>/root.
+pub fn run_in_container_user_namespace(
+    pid: Pid,
+    mut fun: impl FnMut() -> Result<()>,
+) -> Result<()> {
+    const STACK_SIZE: usize = 1024 * 1024;
+    let mut stack = Box::new([0; STACK_SIZE]);
+
+    let cb = Box::new(|| {
+        let res = File::open(format!("/proc/{}/ns/user", pid))
+            .map_err(anyhow::Error::from)
+            .and_then(|ns| Ok(setns(ns.as_raw_fd(), CloneFlags::CLONE_NEWUSER)?))
+            .and_then(|_| fun());
+        match res {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{}", e);
+                1
+            }
+        }
+    });
+    let child = clone(
+        cb,
+        &mut *stack,
+        CloneFlags::empty(),
+        Some(Signal::SIGCHLD as i32),
+    )?;
+
+    match waitpid(child, None)? {
+        WaitStatus::Exited(_, 0) => Ok(()),
+        status => Err(anyhow!("Failed in the user namespace: {:?}", status)),
+    }
+}
@@ -0,0 +1,1084 @@
+use std::fs;
+
+use anyhow::{anyhow, Context, Result};
+use nix::libc;
+use serde::{Deserialize, Serialize};
+
+use crate::syscalls::syscall_number;
+
+// A seccomp profile in the format used by Docker.
+// https://docs.docker.com/engine/security/seccomp/
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    pub default_action: String,
+    #[serde(default)]
+    pub default_errno_ret: Option<u32>,
+    #[serde(default)]
+    pub syscalls: Vec<SyscallRule>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SyscallRule {
+    #[serde(default)]
+    pub names: Vec<String>,
+    // Older profiles have a single name per rule.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub action: String,
+    #[serde(default)]
+    pub errno_ret: Option<u32>,
+    #[serde(default)]
+    pub args: Option<Vec<ArgCondition>>,
+    #[serde(default)]
+    pub includes: RuleFilter,
+    #[serde(default)]
+    pub excludes: RuleFilter,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ArgCondition {
+    pub index: u32,
+    pub value: u64,
+    #[serde(default)]
+    pub value_two: u64,
+    pub op: String,
+}
+
+// A rule only applies if the container has all `caps` and runs on one of
+// `arches` (included), or doesn't if it has any of them (excluded).
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleFilter {
+    #[serde(default)]
+    pub caps: Vec<String>,
+    #[serde(default)]
+    pub arches: Vec<String>,
+}
+
+// Syscalls which the default profile allows, like Docker's default profile.
+// Anything else fails with EPERM.
+const ALLOWED_SYSCALLS: &[&str] = &[
+    "accept",
+    "accept4",
+    "access",
+    "adjtimex",
+    "alarm",
+    "arch_prctl",
+    "bind",
+    "brk",
+    "capget",
+    "capset",
+    "chdir",
+    "chmod",
+    "chown",
+    "clock_getres",
+    "clock_gettime",
+    "clock_nanosleep",
+    "close",
+    "close_range",
+    "connect",
+    "copy_file_range",
+    "creat",
+    "dup",
+    "dup2",
+    "dup3",
+    "epoll_create",
+    "epoll_create1",
+    "epoll_ctl",
+    "epoll_ctl_old",
+    "epoll_pwait",
+    "epoll_pwait2",
+    "epoll_wait",
+    "epoll_wait_old",
+    "eventfd",
+    "eventfd2",
+    "execve",
+    "execveat",
+    "exit",
+    "exit_group",
+    "faccessat",
+    "faccessat2",
+    "fadvise64",
+    "fallocate",
+    "fanotify_mark",
+    "fchdir",
+    "fchmod",
+    "fchmodat",
+    "fchmodat2",
+    "fchown",
+    "fchownat",
+    "fcntl",
+    "fdatasync",
+    "fgetxattr",
+    "flistxattr",
+    "flock",
+    "fork",
+    "fremovexattr",
+    "fsetxattr",
+    "fstat",
+    "fstatfs",
+    "fsync",
+    "ftruncate",
+    "futex",
+    "futex_waitv",
+    "futimesat",
+    "get_robust_list",
+    "get_thread_area",
+    "getcpu",
+    "getcwd",
+    "getdents",
+    "getdents64",
+    "getegid",
+    "geteuid",
+    "getgid",
+    "getgroups",
+    "getitimer",
+    "getpeername",
+    "getpgid",
+    "getpgrp",
+    "getpid",
+    "getppid",
+    "getpriority",
+    "getrandom",
+    "getresgid",
+    "getresuid",
+    "getrlimit",
+    "getrusage",
+    "getsid",
+    "getsockname",
+    "getsockopt",
+    "gettid",
+    "gettimeofday",
+    "getuid",
+    "getxattr",
+    "inotify_add_watch",
+    "inotify_init",
+    "inotify_init1",
+    "inotify_rm_watch",
+    "io_cancel",
+    "io_destroy",
+    "io_getevents",
+    "io_setup",
+    "io_submit",
+    "ioctl",
+    "ioprio_get",
+    "ioprio_set",
+    "kill",
+    "landlock_add_rule",
+    "landlock_create_ruleset",
+    "landlock_restrict_self",
+    "lchown",
+    "lgetxattr",
+    "link",
+    "linkat",
+    "listen",
+    "listxattr",
+    "llistxattr",
+    "lremovexattr",
+    "lseek",
+    "lsetxattr",
+    "lstat",
+    "madvise",
+    "membarrier",
+    "memfd_create",
+    "memfd_secret",
+    "mincore",
+    "mkdir",
+    "mkdirat",
+    "mknod",
+    "mknodat",
+    "mlock",
+    "mlock2",
+    "mlockall",
+    "mmap",
+    "modify_ldt",
+    "mprotect",
+    "mq_getsetattr",
+    "mq_notify",
+    "mq_open",
+    "mq_timedreceive",
+    "mq_timedsend",
+    "mq_unlink",
+    "mremap",
+    "msgctl",
+    "msgget",
+    "msgrcv",
+    "msgsnd",
+    "msync",
+    "munlock",
+    "munlockall",
+    "munmap",
+    "nanosleep",
+    "newfstatat",
+    "open",
+    "openat",
+    "openat2",
+    "pause",
+    "pidfd_open",
+    "pidfd_send_signal",
+    "pipe",
+    "pipe2",
+    "pkey_alloc",
+    "pkey_free",
+    "pkey_mprotect",
+    "poll",
+    "ppoll",
+    "prctl",
+    "pread64",
+    "preadv",
+    "preadv2",
+    "prlimit64",
+    "process_mrelease",
+    "pselect6",
+    "pwrite64",
+    "pwritev",
+    "pwritev2",
+    "read",
+    "readahead",
+    "readlink",
+    "readlinkat",
+    "readv",
+    "recvfrom",
+    "recvmmsg",
+    "recvmsg",
+    "remap_file_pages",
+    "removexattr",
+    "rename",
+    "renameat",
+    "renameat2",
+    "restart_syscall",
+    "rmdir",
+    "rseq",
+    "rt_sigaction",
+    "rt_sigpending",
+    "rt_sigprocmask",
+    "rt_sigqueueinfo",
+    "rt_sigreturn",
+    "rt_sigsuspend",
+    "rt_sigtimedwait",
+    "rt_tgsigqueueinfo",
+    "sched_get_priority_max",
+    "sched_get_priority_min",
+    "sched_getaffinity",
+    "sched_getattr",
+    "sched_getparam",
+    "sched_getscheduler",
+    "sched_rr_get_interval",
+    "sched_setaffinity",
+    "sched_setattr",
+    "sched_setparam",
+    "sched_setscheduler",
+    "sched_yield",
+    "seccomp",
+    "select",
+    "semctl",
+    "semget",
+    "semop",
+    "semtimedop",
+    "sendfile",
+    "sendmmsg",
+    "sendmsg",
+    "sendto",
+    "set_robust_list",
+    "set_thread_area",
+    "set_tid_address",
+    "setdomainname",
+    "setfsgid",
+    "setfsuid",
+    "setgid",
+    "setgroups",
+    "sethostname",
+    "setitimer",
+    "setpgid",
+    "setpriority",
+    "setregid",
+    "setresgid",
+    "setresuid",
+    "setreuid",
+    "setrlimit",
+    "setsid",
+    "setsockopt",
+    "setuid",
+    "setxattr",
+    "shmat",
+    "shmctl",
+    "shmdt",
+    "shmget",
+    "shutdown",
+    "sigaltstack",
+    "signalfd",
+    "signalfd4",
+    "socket",
+    "socketpair",
+    "splice",
+    "stat",
+    "statfs",
+    "statx",
+    "symlink",
+    "symlinkat",
+    "sync",
+    "sync_file_range",
+    "syncfs",
+    "sysinfo",
+    "tee",
+    "tgkill",
+    "time",
+    "timer_create",
+    "timer_delete",
+    "timer_getoverrun",
+    "timer_gettime",
+    "timer_settime",
+    "timerfd_create",
+    "timerfd_gettime",
+    "timerfd_settime",
+    "times",
+    "tkill",
+    "truncate",
+    "umask",
+    "uname",
+    "unlink",
+    "unlinkat",
+    "utime",
+    "utimensat",
+    "utimes",
+    "vfork",
+    "vmsplice",
+    "wait4",
+    "waitid",
+    "write",
+    "writev",
+];
+
+// Syscalls which are only allowed if the container has the capability.
+const CAP_SYSCALLS: &[(&[&str], &str)] = &[
+    (&["acct"], "CAP_SYS_PACCT"),
+    (&["chroot"], "CAP_SYS_CHROOT"),
+    (
+        &[
+            "bpf",
+            "clone",
+            "clone3",
+            "fanotify_init",
+            "fsconfig",
+            "fsmount",
+            "fsopen",
+            "fspick",
+            "lookup_dcookie",
+            "mount",
+            "mount_setattr",
+            "move_mount",
+            "open_tree",
+            "perf_event_open",
+            "pivot_root",
+            "quotactl",
+            "quotactl_fd",
+            "setns",
+            "swapoff",
+            "swapon",
+            "umount2",
+            "unshare",
+        ],
+        "CAP_SYS_ADMIN",
+    ),
+    (
+        &["clock_adjtime", "clock_settime", "settimeofday"],
+        "CAP_SYS_TIME",
+    ),
+    (
+        &["delete_module", "finit_module", "init_module"],
+        "CAP_SYS_MODULE",
+    ),
+    (
+        &[
+            "get_mempolicy",
+            "mbind",
+            "migrate_pages",
+            "move_pages",
+            "set_mempolicy",
+            "set_mempolicy_home_node",
+        ],
+        "CAP_SYS_NICE",
+    ),
+    (&["ioperm", "iopl"], "CAP_SYS_RAWIO"),
+    (
+        &[
+            "kcmp",
+            "pidfd_getfd",
+            "process_madvise",
+            "process_vm_readv",
+            "process_vm_writev",
+            "ptrace",
+        ],
+        "CAP_SYS_PTRACE",
+    ),
+    (&["kexec_file_load", "kexec_load", "reboot"], "CAP_SYS_BOOT"),
+    (
+        &["name_to_handle_at", "open_by_handle_at"],
+        "CAP_DAC_READ_SEARCH",
+    ),
+    (&["syslog"], "CAP_SYSLOG"),
+    (&["vhangup"], "CAP_SYS_TTY_CONFIG"),
+];
+
+// CLONE_NEWNS | CLONE_NEWCGROUP | CLONE_NEWUTS | CLONE_NEWIPC | CLONE_NEWUSER
+// | CLONE_NEWPID | CLONE_NEWNET
+const CLONE_NAMESPACE_FLAGS: u64 = 0x7E02_0000;
+
+// Values of personality() which Docker's default profile allows: PER_LINUX,
+// PER_LINUX32, UNAME26, UNAME26 | PER_LINUX32 and querying the persona.
+const PERSONALITIES: [u64; 5] = [0x0, 0x8, 0x2_0000, 0x2_0008, 0xFFFF_FFFF];
+
+// The built-in profile: only the syscalls a usual program needs are allowed,
+// so that the container can neither break out nor affect the host.
+pub fn default_profile() -> Profile {
+    let mut syscalls = vec![SyscallRule {
+        names: ALLOWED_SYSCALLS.iter().map(|n| n.to_string()).collect(),
+        action: "SCMP_ACT_ALLOW".to_string(),
+        ..Default::default()
+    }];
+
+    for persona in PERSONALITIES.iter() {
+        syscalls.push(SyscallRule {
+            names: vec!["personality".to_string()],
+            action: "SCMP_ACT_ALLOW".to_string(),
+            args: Some(vec![ArgCondition {
+                index: 0,
+                value: *persona,
+                value_two: 0,
+                op: "SCMP_CMP_EQ".to_string(),
+            }]),
+            ..Default::default()
+        });
+    }
+
+    // Creating namespaces needs CAP_SYS_ADMIN. clone3() can't be filtered
+    // by its flags, so it fails with ENOSYS to make libc fall back to clone().
+    let excludes_sys_admin = RuleFilter {
+        caps: vec!["CAP_SYS_ADMIN".to_string()],
+        arches: Vec::new(),
+    };
+    syscalls.push(SyscallRule {
+        names: vec!["clone".to_string()],
+        action: "SCMP_ACT_ALLOW".to_string(),
+        args: Some(vec![ArgCondition {
+            index: 0,
+            value: CLONE_NAMESPACE_FLAGS,
+            value_two: 0,
+            op: "SCMP_CMP_MASKED_EQ".to_string(),
+        }]),
+        excludes: excludes_sys_admin.clone(),
+        ..Default::default()
+    });
+    syscalls.push(SyscallRule {
+        names: vec!["clone3".to_string()],
+        action: "SCMP_ACT_ERRNO".to_string(),
+        errno_ret: Some(libc::ENOSYS as u32),
+        excludes: excludes_sys_admin,
+        ..Default::default()
+    });
+
+    for (names, cap) in CAP_SYSCALLS.iter() {
+        syscalls.push(SyscallRule {
+            names: names.iter().map(|n| n.to_string()).collect(),
+            action: "SCMP_ACT_ALLOW".to_string(),
+            includes: RuleFilter {
+                caps: vec![cap.to_string()],
+                arches: Vec::new(),
+            },
+            ..Default::default()
+        });
+    }
+
+    Profile {
+        default_action: "SCMP_ACT_ERRNO".to_string(),
+        default_errno_ret: Some(libc::EPERM as u32),
+        syscalls: syscalls,
+    }
+}
+
+// Picks the profile from `--security-opt`s, which are "seccomp=unconfined"
+// or "seccomp=<path to a profile>". None means no filter at all, which is
+// also the case for privileged containers.
+pub fn parse_security_options(options: &[String], privileged: bool) -> Result<Option<Profile>> {
+    let mut profile = if privileged {
+        None
+    } else {
+        Some(default_profile())
+    };
+
+    for option in options {
+        let value = match option.strip_prefix("seccomp=") {
+            Some(value) => value,
+            None => return Err(anyhow!("Unsupported security option: {}", option)),
+        };
+        if value == "unconfined" {
+            profile = None;
+            continue;
+        }
+
+        let content = fs::read_to_string(value)
+            .with_context(|| format!("Failed to read seccomp profile: {}", value))?;
+        let custom: Profile = serde_json::from_str(&content)
+            .with_context(|| format!("Invalid seccomp profile: {}", value))?;
+        // Fail early rather than in the container.
+        compile_profile(&custom, &[])?;
+        profile = Some(custom);
+    }
+    Ok(profile)
+}
+
+// Classic BPF, as used by seccomp.
+// https://www.kernel.org/doc/html/latest/userspace-api/seccomp_filter.html
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: libc::c_ushort,
+    filter: *const SockFilter,
+}
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGT_K: u16 = 0x25;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_ALU_AND_K: u16 = 0x54;
+const BPF_RET_K: u16 = 0x06;
+
+const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+const SECCOMP_RET_KILL_THREAD: u32 = 0x0000_0000;
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_TRAP: u32 = 0x0003_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_TRACE: u32 = 0x7ff0_0000;
+const SECCOMP_RET_LOG: u32 = 0x7ffc_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_DATA: u32 = 0x0000_ffff;
+
+// Offsets in struct seccomp_data.
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+const SECCOMP_DATA_ARGS: u32 = 16;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const AUDIT_ARCH: u32 = 0;
+
+#[cfg(target_arch = "x86_64")]
+const ARCH_NAMES: [&str; 2] = ["amd64", "x86_64"];
+#[cfg(target_arch = "aarch64")]
+const ARCH_NAMES: [&str; 2] = ["arm64", "aarch64"];
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+const ARCH_NAMES: [&str; 0] = [];
+
+// Syscalls of the x32 ABI share the arch with x86_64 and are told apart by
+// this bit in their numbers.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// A jump to the instruction right after the rule.
+const JUMP_TO_NEXT_RULE: u8 = u8::MAX;
+
+// Builds a filter which checks the rules in order and takes the action of
+// the first matching one. Rules which don't apply to `caps` or to this
+// architecture are left out, as are syscalls this architecture doesn't have.
+pub fn compile_profile(profile: &Profile, caps: &[String]) -> Result<Vec<SockFilter>> {
+    if AUDIT_ARCH == 0 {
+        return Err(anyhow!("seccomp is not supported on this architecture"));
+    }
+
+    let default_action = parse_action(&profile.default_action, profile.default_errno_ret)?;
+    let mut filter = vec![
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+        stmt(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+        jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+        stmt(BPF_RET_K, SECCOMP_RET_ERRNO | libc::EPERM as u32),
+    ];
+
+    for rule in &profile.syscalls {
+        if !rule_applies(rule, caps) {
+            continue;
+        }
+        let action = parse_action(&rule.action, rule.errno_ret)?;
+
+        let mut names = rule.names.clone();
+        names.extend(rule.name.clone());
+        for nr in names.iter().filter_map(|name| syscall_number(name)) {
+            let mut block = vec![
+                stmt(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+                jump(BPF_JMP_JEQ_K, nr as u32, 0, JUMP_TO_NEXT_RULE),
+            ];
+            for cond in rule.args.iter().flatten() {
+                block.extend(compile_condition(cond)?);
+            }
+            block.push(stmt(BPF_RET_K, action));
+            filter.extend(resolve_jumps(block)?);
+        }
+    }
+
+    filter.push(stmt(BPF_RET_K, default_action));
+    if filter.len() > u16::MAX as usize {
+        return Err(anyhow!("seccomp profile is too large"));
+    }
+    Ok(filter)
+}
+
+fn rule_applies(rule: &SyscallRule, caps: &[String]) -> bool {
+    let has_cap = |cap: &String| caps.contains(cap);
+    let is_this_arch = |arch: &String| ARCH_NAMES.contains(&arch.as_str());
+
+    rule.includes.caps.iter().all(has_cap)
+        && (rule.includes.arches.is_empty() || rule.includes.arches.iter().any(is_this_arch))
+        && !rule.excludes.caps.iter().any(has_cap)
+        && !rule.excludes.arches.iter().any(is_this_arch)
+}
+
+fn parse_action(action: &str, errno_ret: Option<u32>) -> Result<u32> {
+    // The value is returned in the low bits, and a larger one would change
+    // the action.
+    if let Some(errno_ret) = errno_ret.filter(|ret| *ret > SECCOMP_RET_DATA) {
+        return Err(anyhow!(
+            "Invalid errnoRet in seccomp profile: {}",
+            errno_ret
+        ));
+    }
+    match action {
+        "SCMP_ACT_ALLOW" => Ok(SECCOMP_RET_ALLOW),
+        "SCMP_ACT_ERRNO" => Ok(SECCOMP_RET_ERRNO | errno_ret.unwrap_or(libc::EPERM as u32)),
+        "SCMP_ACT_KILL" | "SCMP_ACT_KILL_THREAD" => Ok(SECCOMP_RET_KILL_THREAD),
+        "SCMP_ACT_KILL_PROCESS" => Ok(SECCOMP_RET_KILL_PROCESS),
+        "SCMP_ACT_TRAP" => Ok(SECCOMP_RET_TRAP),
+        "SCMP_ACT_TRACE" => Ok(SECCOMP_RET_TRACE | errno_ret.unwrap_or(0)),
+        "SCMP_ACT_LOG" => Ok(SECCOMP_RET_LOG),
+        _ => Err(anyhow!("Unknown seccomp action: {}", action)),
+    }
+}
+
+// Arguments are 64 bits wide but BPF can only load 32 bits at once, so the
+// high half is compared first. Every instruction either falls through when
+// the condition may still hold or jumps to the next rule when it can't.
+fn compile_condition(cond: &ArgCondition) -> Result<Vec<SockFilter>> {
+    if cond.index > 5 {
+        return Err(anyhow!("Invalid seccomp argument index: {}", cond.index));
+    }
+    let low = SECCOMP_DATA_ARGS + cond.index * 8;
+    let high = low + 4;
+    let (value_high, value_low) = ((cond.value >> 32) as u32, cond.value as u32);
+    let next = JUMP_TO_NEXT_RULE;
+
+    let insns = match cond.op.as_str() {
+        "SCMP_CMP_EQ" => vec![
+            stmt(BPF_LD_W_ABS, high),
+            jump(BPF_JMP_JEQ_K, value_high, 0, next),
+            stmt(BPF_LD_W_ABS, low),
+            jump(BPF_JMP_JEQ_K, value_low, 0, next),
+        ],
+        "SCMP_CMP_NE" => vec![
+            stmt(BPF_LD_W_ABS, high),
+            jump(BPF_JMP_JEQ_K, value_high, 0, 2),
+            stmt(BPF_LD_W_ABS, low),
+            jump(BPF_JMP_JEQ_K, value_low, next, 0),
+        ],
+        "SCMP_CMP_MASKED_EQ" => {
+            let (datum_high, datum_low) = ((cond.value_two >> 32) as u32, cond.value_two as u32);
+            vec![
+                stmt(BPF_LD_W_ABS, high),
+                stmt(BPF_ALU_AND_K, value_high),
+                jump(BPF_JMP_JEQ_K, datum_high, 0, next),
+                stmt(BPF_LD_W_ABS, low),
+                stmt(BPF_ALU_AND_K, value_low),
+                jump(BPF_JMP_JEQ_K, datum_low, 0, next),
+            ]
+        }
+        // arg > value (or >=): decided by the high half unless it's equal.
+        "SCMP_CMP_GT" | "SCMP_CMP_GE" => {
+            let cmp = if cond.op == "SCMP_CMP_GT" {
+                BPF_JMP_JGT_K
+            } else {
+                BPF_JMP_JGE_K
+            };
+            vec![
+                stmt(BPF_LD_W_ABS, high),
+                jump(BPF_JMP_JGT_K, value_high, 3, 0),
+                jump(BPF_JMP_JEQ_K, value_high, 0, next),
+                stmt(BPF_LD_W_ABS, low),
+                jump(cmp, value_low, 0, next),
+            ]
+        }
+        // arg < value is !(arg >= value), and arg <= value is !(arg > value).
+        "SCMP_CMP_LT" | "SCMP_CMP_LE" => {
+            let cmp = if cond.op == "SCMP_CMP_LT" {
+                BPF_JMP_JGE_K
+            } else {
+                BPF_JMP_JGT_K
+            };
+            vec![
+                stmt(BPF_LD_W_ABS, high),
+                jump(BPF_JMP_JGT_K, value_high, next, 0),
+                jump(BPF_JMP_JEQ_K, value_high, 0, 2),
+                stmt(BPF_LD_W_ABS, low),
+                jump(cmp, value_low, next, 0),
+            ]
+        }
+        _ => return Err(anyhow!("Unknown seccomp operator: {}", cond.op)),
+    };
+    Ok(insns)
+}
+
+// Turns JUMP_TO_NEXT_RULE into the offset to the end of `block`.
+fn resolve_jumps(mut block: Vec<SockFilter>) -> Result<Vec<SockFilter>> {
+    let len = block.len();
+    if len > JUMP_TO_NEXT_RULE as usize {
+        return Err(anyhow!("Too many conditions in a seccomp rule"));
+    }
+    for (i, insn) in block.iter_mut().enumerate() {
+        if insn.code & 0x07 != 0x05 {
+            continue;
+        }
+        let offset = (len - i - 1) as u8;
+        if insn.jt == JUMP_TO_NEXT_RULE {
+            insn.jt = offset;
+        }
+        if insn.jf == JUMP_TO_NEXT_RULE {
+            insn.jf = offset;
+        }
+    }
+    Ok(block)
+}
+
+fn stmt(code: u16, k: u32) -> SockFilter {
+    SockFilter {
+        code: code,
+        jt: 0,
+        jf: 0,
+        k: k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter {
+        code: code,
+        jt: jt,
+        jf: jf,
+        k: k,
+    }
+}
+
+// Must be called while the process still has CAP_SYS_ADMIN, i.e. before
+// switching to another user, since no_new_privs isn't set.
+pub fn install_filter(filter: &[SockFilter]) -> Result<()> {
+    let prog = SockFprog {
+        len: filter.len() as libc::c_ushort,
+        filter: filter.as_ptr(),
+    };
+    let res = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            SECCOMP_MODE_FILTER,
+            &prog as *const SockFprog,
+        )
+    };
+    if res != 0 {
+        return Err(anyhow!(
+            "Failed to install seccomp filter: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPERM: u32 = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    // struct seccomp_data, with the arguments in the byte order of this
+    // machine like the kernel fills it.
+    fn seccomp_data(arch: u32, nr: u32, args: [u64; 6]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&nr.to_ne_bytes());
+        data.extend_from_slice(&arch.to_ne_bytes());
+        data.extend_from_slice(&0u64.to_ne_bytes());
+        for arg in args.iter() {
+            data.extend_from_slice(&arg.to_ne_bytes());
+        }
+        data
+    }
+
+    // Interprets the instructions compile_profile() emits.
+    fn run_filter(filter: &[SockFilter], data: &[u8]) -> u32 {
+        let mut acc = 0u32;
+        let mut pc = 0;
+        loop {
+            let insn = filter[pc];
+            pc += 1;
+            let taken = match insn.code {
+                BPF_LD_W_ABS => {
+                    let k = insn.k as usize;
+                    acc = u32::from_ne_bytes([data[k], data[k + 1], data[k + 2], data[k + 3]]);
+                    continue;
+                }
+                BPF_ALU_AND_K => {
+                    acc &= insn.k;
+                    continue;
+                }
+                BPF_RET_K => return insn.k,
+                BPF_JMP_JEQ_K => acc == insn.k,
+                BPF_JMP_JGT_K => acc > insn.k,
+                BPF_JMP_JGE_K => acc >= insn.k,
+                code => panic!("unexpected instruction: {:#x}", code),
+            };
+            pc += if taken { insn.jt } else { insn.jf } as usize;
+        }
+    }
+
+    fn run_syscall(filter: &[SockFilter], name: &str, args: [u64; 6]) -> u32 {
+        let nr = syscall_number(name).unwrap() as u32;
+        run_filter(filter, &seccomp_data(AUDIT_ARCH, nr, args))
+    }
+
+    fn rule(
+        name: &str,
+        action: &str,
+        errno_ret: Option<u32>,
+        args: Vec<ArgCondition>,
+    ) -> SyscallRule {
+        SyscallRule {
+            names: vec![name.to_string()],
+            action: action.to_string(),
+            errno_ret: errno_ret,
+            args: Some(args),
+            ..Default::default()
+        }
+    }
+
+    fn condition(index: u32, op: &str, value: u64, value_two: u64) -> ArgCondition {
+        ArgCondition {
+            index: index,
+            value: value,
+            value_two: value_two,
+            op: op.to_string(),
+        }
+    }
+
+    fn allow_by_default(syscalls: Vec<SyscallRule>) -> Vec<SockFilter> {
+        let profile = Profile {
+            default_action: "SCMP_ACT_ALLOW".to_string(),
+            default_errno_ret: None,
+            syscalls: syscalls,
+        };
+        compile_profile(&profile, &[]).unwrap()
+    }
+
+    #[test]
+    fn test_comparisons_of_both_halves() {
+        let values = [
+            0,
+            1,
+            0xFFFF_FFFF,
+            0x1_0000_0000,
+            0x1_0000_0001,
+            0x1_FFFF_FFFF,
+            0x2_0000_0000,
+            u64::MAX,
+        ];
+        let ops: [(&str, fn(u64, u64) -> bool); 6] = [
+            ("SCMP_CMP_EQ", |arg, value| arg == value),
+            ("SCMP_CMP_NE", |arg, value| arg != value),
+            ("SCMP_CMP_GT", |arg, value| arg > value),
+            ("SCMP_CMP_GE", |arg, value| arg >= value),
+            ("SCMP_CMP_LT", |arg, value| arg < value),
+            ("SCMP_CMP_LE", |arg, value| arg <= value),
+        ];
+
+        for (op, expected) in ops.iter() {
+            for value in values.iter() {
+                let filter = allow_by_default(vec![rule(
+                    "personality",
+                    "SCMP_ACT_ERRNO",
+                    None,
+                    vec![condition(2, op, *value, 0)],
+                )]);
+                for arg in values.iter() {
+                    let action = run_syscall(&filter, "personality", [0, 0, *arg, 0, 0, 0]);
+                    let matched = action == EPERM;
+                    assert_eq!(
+                        matched,
+                        expected(*arg, *value),
+                        "{} {:#x} {:#x}",
+                        op,
+                        arg,
+                        value
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_masked_eq() {
+        let filter = allow_by_default(vec![rule(
+            "personality",
+            "SCMP_ACT_ERRNO",
+            None,
+            vec![condition(
+                0,
+                "SCMP_CMP_MASKED_EQ",
+                0xF_0000_00F0,
+                0x1_0000_0010,
+            )],
+        )]);
+        for (arg, matched) in [
+            (0x1_0000_0010, true),
+            (0x1_FFFF_FF1F, true),
+            (0x1_0000_0020, false),
+            (0x2_0000_0010, false),
+            (0x10, false),
+        ]
+        .iter()
+        {
+            let action = run_syscall(&filter, "personality", [*arg, 0, 0, 0, 0, 0]);
+            assert_eq!(action == EPERM, *matched, "{:#x}", arg);
+        }
+    }
+
+    // Conditions which don't hold jump over the rest of the rule to the next
+    // one, and all of them have to hold.
+    #[test]
+    fn test_jumps_to_next_rule() {
+        let filter = allow_by_default(vec![
+            rule(
+                "personality",
+                "SCMP_ACT_ERRNO",
+                Some(1),
+                vec![
+                    condition(0, "SCMP_CMP_EQ", 1, 0),
+                    condition(1, "SCMP_CMP_GT", 0x1_0000_0000, 0),
+                    condition(2, "SCMP_CMP_MASKED_EQ", 0xFF, 0x0F),
+                ],
+            ),
+            rule("personality", "SCMP_ACT_ERRNO", Some(2), Vec::new()),
+            rule("getpid", "SCMP_ACT_ERRNO", Some(3), Vec::new()),
+        ]);
+
+        let first = SECCOMP_RET_ERRNO | 1;
+        let second = SECCOMP_RET_ERRNO | 2;
+        let personality = |args| run_syscall(&filter, "personality", args);
+        assert_eq!(personality([1, 0x1_0000_0001, 0xF0F, 0, 0, 0]), first);
+        assert_eq!(personality([2, 0x1_0000_0001, 0xF0F, 0, 0, 0]), second);
+        assert_eq!(personality([1, 0x1_0000_0000, 0xF0F, 0, 0, 0]), second);
+        assert_eq!(personality([1, 0x1_0000_0001, 0xFF, 0, 0, 0]), second);
+        assert_eq!(
+            run_syscall(&filter, "getpid", [0; 6]),
+            SECCOMP_RET_ERRNO | 3
+        );
+        assert_eq!(run_syscall(&filter, "getppid", [0; 6]), SECCOMP_RET_ALLOW);
+    }
+
+    #[test]
+    fn test_other_architectures() {
+        let filter = allow_by_default(Vec::new());
+        let nr = syscall_number("getpid").unwrap() as u32;
+        assert_eq!(
+            run_filter(&filter, &seccomp_data(0x4000_0003, nr, [0; 6])),
+            SECCOMP_RET_KILL_PROCESS
+        );
+        assert_eq!(
+            run_filter(
+                &filter,
+                &seccomp_data(AUDIT_ARCH, nr | X32_SYSCALL_BIT, [0; 6])
+            ),
+            EPERM
+        );
+    }
+
+    #[test]
+    fn test_default_profile() {
+        let filter = compile_profile(&default_profile(), &[]).unwrap();
+        let clone_newuser = [libc::CLONE_NEWUSER as u64, 0, 0, 0, 0, 0];
+        assert_eq!(run_syscall(&filter, "clone", clone_newuser), EPERM);
+        assert_eq!(
+            run_syscall(&filter, "clone", [libc::SIGCHLD as u64, 0, 0, 0, 0, 0]),
+            SECCOMP_RET_ALLOW
+        );
+        assert_eq!(
+            run_syscall(&filter, "clone3", [0; 6]),
+            SECCOMP_RET_ERRNO | libc::ENOSYS as u32
+        );
+        for name in [
+            "mount",
+            "fsopen",
+            "open_tree",
+            "pidfd_getfd",
+            "io_uring_setup",
+            "chroot",
+            "keyctl",
+        ]
+        .iter()
+        {
+            assert_eq!(run_syscall(&filter, name, [0; 6]), EPERM, "{}", name);
+        }
+        assert_eq!(run_syscall(&filter, "getpid", [0; 6]), SECCOMP_RET_ALLOW);
+        assert_eq!(
+            run_syscall(&filter, "personality", [0x8, 0, 0, 0, 0, 0]),
+            SECCOMP_RET_ALLOW
+        );
+        // ADDR_NO_RANDOMIZE
+        assert_eq!(
+            run_syscall(&filter, "personality", [0x4_0000, 0, 0, 0, 0, 0]),
+            EPERM
+        );
+
+        let caps = ["CAP_SYS_ADMIN".to_string()];
+        let filter = compile_profile(&default_profile(), &caps).unwrap();
+        assert_eq!(
+            run_syscall(&filter, "clone", clone_newuser),
+            SECCOMP_RET_ALLOW
+        );
+        assert_eq!(run_syscall(&filter, "fsopen", [0; 6]), SECCOMP_RET_ALLOW);
+        assert_eq!(run_syscall(&filter, "io_uring_setup", [0; 6]), EPERM);
+        assert_eq!(run_syscall(&filter, "chroot", [0; 6]), EPERM);
+    }
+
+    // The kernel refuses filters longer than BPF_MAXINSNS.
+    #[test]
+    fn test_default_profile_size() {
+        let caps: Vec<String> = CAP_SYSCALLS.iter().map(|(_, c)| c.to_string()).collect();
+        let filter = compile_profile(&default_profile(), &caps).unwrap();
+        assert!(filter.len() <= 4096, "{}", filter.len());
+    }
+
+    // A misspelled name would silently be left out of the filter.
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_default_profile_names() {
+        let names = CAP_SYSCALLS.iter().flat_map(|(names, _)| names.iter());
+        for name in ALLOWED_SYSCALLS.iter().chain(names) {
+            assert!(syscall_number(name).is_some(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_errno_ret_out_of_range() {
+        assert_eq!(
+            parse_action("SCMP_ACT_ERRNO", Some(0xFFFF)).unwrap(),
+            SECCOMP_RET_ERRNO | 0xFFFF
+        );
+        assert!(parse_action("SCMP_ACT_ERRNO", Some(0x1_0000)).is_err());
+        assert!(parse_action("SCMP_ACT_TRACE", Some(0x7000_0000)).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    fs, thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    cgroup::{read_cgroup_stats, CgroupStats},
+    container::{fetch_containers, ContainerState},
+    network::read_veth_stats,
+};
+
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+// Prints the resource usage of the given containers (every running one if
+// none is given) every second, or once with `no_stream`. CPU usage is
+// measured over the interval, so the first table comes after a second.
+pub fn print_stats(container_ids: &[String], no_stream: bool) -> Result<()> {
+    let mut previous = HashMap::new();
+    for id in fetch_target_containers(container_ids)? {
+        if let Ok(stats) = read_cgroup_stats(&id) {
+            previous.insert(id, (stats.cpu_usage_nsec, Instant::now()));
+        }
+    }
+
+    loop {
+        thread::sleep(STATS_INTERVAL);
+
+        let mut rows = Vec::new();
+        let mut current = HashMap::new();
+        for id in fetch_target_containers(container_ids)? {
+            let stats = match read_cgroup_stats(&id) {
+                Ok(stats) => stats,
+                // The container has exited in the meantime.
+                Err(_) => {
+                    rows.push(format!("{}\t--\t-- / --\t--\t--\t--\t--", id));
+                    continue;
+                }
+            };
+            let now = Instant::now();
+            let cpu_percent = match previous.get(&id) {
+                Some((usage, at)) => {
+                    let elapsed = now.duration_since(*at).as_nanos() as f64;
+                    stats.cpu_usage_nsec.saturating_sub(*usage) as f64 / elapsed * 100.0
+                }
+                None => 0.0,
+            };
+            rows.push(format_row(&id, cpu_percent, &stats));
+            current.insert(id, (stats.cpu_usage_nsec, now));
+        }
+        previous = current;
+
+        if !no_stream {
+            // Clears the terminal.
+            print!("\x1b[2J\x1b[H");
+        }
+        println!("CONTAINER ID\tCPU %\tMEM USAGE / LIMIT\tMEM %\tNET I/O\tBLOCK I/O\tPIDS");
+        for row in rows {
+            println!("{}", row);
+        }
+
+        if no_stream {
+            return Ok(());
+        }
+    }
+}
+
+fn fetch_target_containers(container_ids: &[String]) -> Result<Vec<String>> {
+    let containers = fetch_containers()?;
+    if container_ids.is_empty() {
+        return Ok(containers
+            .into_iter()
+            .filter(|c| c.state == ContainerState::Running || c.state == ContainerState::Paused)
+            .map(|c| c.id)
+            .collect());
+    }
+
+    for id in container_ids {
+        if !containers.iter().any(|c| &c.id == id) {
+            return Err(anyhow!("No such container: {}", id));
+        }
+    }
+    Ok(container_ids.to_vec())
+}
+
+fn format_row(container_id: &str, cpu_percent: f64, stats: &CgroupStats) -> String {
+    let memory_limit = stats.memory_limit.or_else(host_memory).unwrap_or(0);
+    let memory_percent = if memory_limit > 0 {
+        stats.memory_usage as f64 / memory_limit as f64 * 100.0
+    } else {
+        0.0
+    };
+    let net_io = match read_veth_stats(container_id) {
+        Some((rx, tx)) => format!("{} / {}", format_size(rx), format_size(tx)),
+        None => "-- / --".to_string(),
+    };
+
+    format!(
+        "{}\t{:.2}%\t{} / {}\t{:.2}%\t{}\t{} / {}\t{}",
+        container_id,
+        cpu_percent,
+        format_binary_size(stats.memory_usage),
+        format_binary_size(memory_limit),
+        memory_percent,
+        net_io,
+        format_size(stats.block_read),
+        format_size(stats.block_write),
+        stats.pids
+    )
+}
+
+// MemTotal in /proc/meminfo, which is the limit of a container without one.
+fn host_memory() -> Option<u64> {
+    let meminfo = fs::read_to_string("/proc/meminfo").ok()?;
+    let kb: u64 = meminfo
+        .lines()
+        .find(|line| line.starts_with("MemTotal:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+// Sizes are shown the same way as Docker does: memory in binary units and
+// I/O in decimal ones.
+fn format_binary_size(bytes: u64) -> String {
+    format_with_units(bytes, 1024.0, &["B", "KiB", "MiB", "GiB", "TiB"])
+}
+
+fn format_size(bytes: u64) -> String {
+    format_with_units(bytes, 1000.0, &["B", "kB", "MB", "GB", "TB"])
+}
+
+fn format_with_units(bytes: u64, base: f64, units: &[&str]) -> String {
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= base && unit < units.len() - 1 {
+        size /= base;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{}{}", bytes, units[0])
+    } else {
+        format!("{:.2}{}", size, units[unit])
+    }
+}
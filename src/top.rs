@@ -0,0 +1,138 @@
+use std::{fs, process::Command};
+
+use anyhow::{anyhow, Context, Result};
+use nix::unistd::{sysconf, SysconfVar, Uid, User};
+
+use crate::{
+    cgroup::fetch_cgroup_pids,
+    container::{fetch_container_state, ContainerState},
+};
+
+// Lists the processes in the container. With `ps_args`, ps is run on the host
+// with them and only the lines of the container's processes are shown, the
+// same way as Docker does.
+pub fn print_container_processes(container_id: &str, ps_args: &[String]) -> Result<()> {
+    match fetch_container_state(container_id)? {
+        Some(ContainerState::Running) | Some(ContainerState::Paused) => {}
+        Some(_) => return Err(anyhow!("container is not running: {}", container_id)),
+        None => return Err(anyhow!("container not found: {}", container_id)),
+    }
+
+    let mut pids = fetch_cgroup_pids(container_id)?;
+    pids.sort_unstable();
+
+    if !ps_args.is_empty() {
+        return print_ps_output(&pids, ps_args);
+    }
+
+    println!("PID\tCONTAINER PID\tUSER\tTIME\tCMD");
+    for pid in pids {
+        // The process may have exited in the meantime.
+        if let Ok(line) = format_process(pid) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
+
+fn format_process(pid: u32) -> Result<String> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))?;
+    let field = |name: &str| -> Option<String> {
+        status
+            .lines()
+            .find(|line| line.starts_with(name))
+            .map(|line| line[name.len()..].trim().to_string())
+    };
+
+    // "NSpid:" lists the pid in each nested PID namespace, innermost last.
+    let container_pid = field("NSpid:")
+        .and_then(|pids| pids.split_whitespace().last().map(|p| p.to_string()))
+        .unwrap_or_else(|| "-".to_string());
+    let uid: u32 = field("Uid:")
+        .and_then(|uids| uids.split_whitespace().next().map(|u| u.to_string()))
+        .unwrap_or_default()
+        .parse()?;
+    let user = match User::from_uid(Uid::from_raw(uid)) {
+        Ok(Some(user)) => user.name,
+        _ => uid.to_string(),
+    };
+
+    let cmdline = fs::read_to_string(format!("/proc/{}/cmdline", pid))?;
+    let cmd = if cmdline.is_empty() {
+        // Kernel threads and zombies have no command line.
+        format!("[{}]", field("Name:").unwrap_or_default())
+    } else {
+        cmdline.trim_end_matches('\0').replace('\0', " ")
+    };
+
+    Ok(format!(
+        "{}\t{}\t{}\t{}\t{}",
+        pid,
+        container_pid,
+        user,
+        format_cpu_time(read_cpu_ticks(pid)?),
+        cmd
+    ))
+}
+
+// utime + stime in /proc/<pid>/stat, in clock ticks. The command name in the
+// second field is in parentheses and may contain spaces.
+fn read_cpu_ticks(pid: u32) -> Result<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    let fields: Vec<&str> = stat[stat.rfind(')').unwrap_or(0) + 1..]
+        .split_whitespace()
+        .collect();
+    if fields.len() < 13 {
+        return Err(anyhow!("Invalid /proc/{}/stat", pid));
+    }
+    // utime and stime are the 14th and 15th fields of the whole line.
+    Ok(fields[11].parse::<u64>()? + fields[12].parse::<u64>()?)
+}
+
+// [DD-]HH:MM:SS like ps.
+fn format_cpu_time(ticks: u64) -> String {
+    let ticks_per_sec = match sysconf(SysconfVar::CLK_TCK) {
+        Ok(Some(n)) if n > 0 => n as u64,
+        _ => 100,
+    };
+    let secs = ticks / ticks_per_sec;
+    let (days, hours, mins, secs) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60, secs % 60);
+    if days > 0 {
+        format!("{}-{:02}:{:02}:{:02}", days, hours, mins, secs)
+    } else {
+        format!("{:02}:{:02}:{:02}", hours, mins, secs)
+    }
+}
+
+fn print_ps_output(pids: &[u32], ps_args: &[String]) -> Result<()> {
+    let output = Command::new("ps")
+        .args(ps_args)
+        .output()
+        .with_context(|| "Failed to run ps")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "ps failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    let header = lines.next().unwrap_or_default();
+    let pid_column = match header.split_whitespace().position(|h| h == "PID") {
+        Some(column) => column,
+        None => return Err(anyhow!("Couldn't find the PID field in the output of ps")),
+    };
+
+    println!("{}", header);
+    for line in lines {
+        let pid = line
+            .split_whitespace()
+            .nth(pid_column)
+            .and_then(|pid| pid.parse::<u32>().ok());
+        if pid.map_or(false, |pid| pids.contains(&pid)) {
+            println!("{}", line);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::{
+    os::unix::io::RawFd,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, Ordering},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+};
+
+use anyhow::{anyhow, Result};
+use nix::{
+    ioctl_read_bad, ioctl_write_int_bad, ioctl_write_ptr_bad, libc,
+    pty::{openpty, Winsize},
+    sys::{
+        signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal},
+        socket::{recvmsg, sendmsg, ControlMessage, ControlMessageOwned, MsgFlags},
+        termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg, Termios},
+        uio::IoVec,
+    },
+    unistd::{close, dup2, isatty, pipe, read, setsid, write},
+};
+use once_cell::sync::Lazy;
+
+use crate::{
+    attach::AttachServer,
+    logs::{copy_output, LogWriter},
+};
+
+ioctl_write_int_bad!(tiocsctty, libc::TIOCSCTTY);
+ioctl_read_bad!(tiocgwinsz, libc::TIOCGWINSZ, Winsize);
+ioctl_write_ptr_bad!(tiocswinsz, libc::TIOCSWINSZ, Winsize);
+
+// Write end of the pipe which the SIGWINCH handler notifies.
+static WINCH_PIPE_FD: AtomicI32 = AtomicI32::new(-1);
+
+// The pty master which the caller's stdin and window size go to. The threads
+// forwarding them are started once per process, and a restarted container
+// only replaces the master.
+static PTY_MASTER: Lazy<Mutex<Option<RawFd>>> = Lazy::new(|| Mutex::new(None));
+static FORWARDING_STDIN: AtomicBool = AtomicBool::new(false);
+static FORWARDING_WINDOW_SIZE: AtomicBool = AtomicBool::new(false);
+
+// Allocates a pty from the container's devpts and makes its slave side the
+// controlling terminal and stdio of the calling process. The master side is
+// handed over to the supervisor through `console_socket`.
+// Must be called in the container after its /dev has been set up.
+pub fn setup_container_tty(console_socket: RawFd) -> Result<()> {
+    let pty = openpty(None, None)?;
+    send_fd(console_socket, pty.master)?;
+    close(pty.master)?;
+    close(console_socket)?;
+
+    setsid()?;
+    unsafe { tiocsctty(pty.slave, 0) }?;
+    for fd in 0..=2 {
+        dup2(pty.slave, fd)?;
+    }
+    close(pty.slave)?;
+    Ok(())
+}
+
+fn send_fd(socket: RawFd, fd: RawFd) -> Result<()> {
+    let iov = [IoVec::from_slice(b"\0")];
+    let fds = [fd];
+    let cmsg = [ControlMessage::ScmRights(&fds)];
+    sendmsg(socket, &iov, &cmsg, MsgFlags::empty(), None)?;
+    Ok(())
+}
+
+pub fn receive_fd(socket: RawFd) -> Result<RawFd> {
+    let mut buf = [0u8; 1];
+    let iov = [IoVec::from_mut_slice(&mut buf)];
+    let mut cmsg_buffer = nix::cmsg_space!([RawFd; 1]);
+    let msg = recvmsg(socket, &iov, Some(&mut cmsg_buffer), MsgFlags::empty())?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(fds) = cmsg {
+            if let Some(fd) = fds.first() {
+                return Ok(*fd);
+            }
+        }
+    }
+    Err(anyhow!("No pty was received from the container"))
+}
+
+// Puts the terminal on `fd` into raw mode so that every key press is passed
+// to the container. The original mode is restored when this is dropped.
+pub struct RawTerminal {
+    fd: RawFd,
+    original: Termios,
+}
+
+impl RawTerminal {
+    pub fn new(fd: RawFd) -> Result<Option<Self>> {
+        if !isatty(fd)? {
+            return Ok(None);
+        }
+        let original = tcgetattr(fd)?;
+        let mut raw = original.clone();
+        cfmakeraw(&mut raw);
+        tcsetattr(fd, SetArg::TCSANOW, &raw)?;
+        Ok(Some(RawTerminal { fd, original }))
+    }
+}
+
+impl Drop for RawTerminal {
+    fn drop(&mut self) {
+        let _ = tcsetattr(self.fd, SetArg::TCSANOW, &self.original);
+    }
+}
+
+// Relays the master's output to the caller's stdout (and the log and the
+// attached clients) and, if `forward_stdin` is set, the caller's stdin to the
+// master. The returned thread finishes when every process in the container
+// has closed the slave.
+pub fn relay_pty(
+    master: RawFd,
+    forward_stdin: bool,
+    log: Option<Arc<LogWriter>>,
+    attach: Option<Arc<AttachServer>>,
+) -> JoinHandle<()> {
+    *PTY_MASTER.lock().unwrap() = Some(master);
+    if forward_stdin && !FORWARDING_STDIN.swap(true, Ordering::SeqCst) {
+        thread::spawn(forward_stdin_to_pty);
+    }
+    copy_output(master, libc::STDOUT_FILENO, "stdout", log, attach)
+}
+
+// Stops forwarding to `master` before closing it, so that nothing is written
+// to another file which gets its number.
+pub fn close_pty(master: RawFd) -> Result<()> {
+    *PTY_MASTER.lock().unwrap() = None;
+    close(master)?;
+    Ok(())
+}
+
+// Input while no container is running is dropped.
+fn forward_stdin_to_pty() {
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match read(libc::STDIN_FILENO, &mut buf) {
+            Ok(0) | Err(_) => return,
+            Ok(n) => n,
+        };
+        if let Some(master) = *PTY_MASTER.lock().unwrap() {
+            let _ = write_all(master, &buf[..n]);
+        }
+    }
+}
+
+pub fn write_all(fd: RawFd, mut buf: &[u8]) -> Result<()> {
+    while !buf.is_empty() {
+        let n = write(fd, buf)?;
+        buf = &buf[n..];
+    }
+    Ok(())
+}
+
+// Copies the window size of the caller's terminal to the pty now and
+// whenever the caller receives SIGWINCH. The handler is only installed once,
+// and follows the master relay_pty() has been given last.
+pub fn forward_window_size(master: RawFd) -> Result<()> {
+    if !isatty(libc::STDIN_FILENO)? {
+        return Ok(());
+    }
+    unsafe { tiocswinsz(master, &read_window_size(libc::STDIN_FILENO)?) }?;
+    if FORWARDING_WINDOW_SIZE.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    watch_window_size(|size| {
+        if let Some(master) = *PTY_MASTER.lock().unwrap() {
+            unsafe { tiocswinsz(master, size) }?;
+        }
+        Ok(())
+    })
+}
+
+// Calls `f` with the window size of the caller's terminal now and whenever
+// the caller receives SIGWINCH. Must only be called once per process.
+pub fn watch_window_size<F>(f: F) -> Result<()>
+where
+    F: Fn(&Winsize) -> Result<()> + Send + 'static,
+{
+    if !isatty(libc::STDIN_FILENO)? {
+        return Ok(());
+    }
+    f(&read_window_size(libc::STDIN_FILENO)?)?;
+
+    let (read_fd, write_fd) = pipe()?;
+    WINCH_PIPE_FD.store(write_fd, Ordering::SeqCst);
+    let action = SigAction::new(
+        SigHandler::Handler(handle_sigwinch),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    unsafe { sigaction(Signal::SIGWINCH, &action) }?;
+
+    thread::spawn(move || {
+        let mut buf = [0u8; 1];
+        while let Ok(1) = read(read_fd, &mut buf) {
+            if let Ok(size) = read_window_size(libc::STDIN_FILENO) {
+                let _ = f(&size);
+            }
+        }
+    });
+    Ok(())
+}
+
+extern "C" fn handle_sigwinch(_: libc::c_int) {
+    let fd = WINCH_PIPE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        let _ = write(fd, b"x");
+    }
+}
+
+fn read_window_size(fd: RawFd) -> Result<Winsize> {
+    let mut size = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    unsafe { tiocgwinsz(fd, &mut size) }?;
+    Ok(size)
+}
+
+pub fn set_window_size(fd: RawFd, rows: u16, cols: u16) -> Result<()> {
+    let size = Winsize {
+        ws_row: rows,
+        ws_col: cols,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    unsafe { tiocswinsz(fd, &size) }?;
+    Ok(())
+}
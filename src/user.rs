@@ -0,0 +1,105 @@
+use std::fs;
+
+use anyhow::{anyhow, Result};
+use nix::unistd::{setgid, setgroups, setuid, Gid, Uid};
+
+pub struct User {
+    pub uid: Uid,
+    pub gid: Gid,
+    // Supplementary groups.
+    pub groups: Vec<Gid>,
+}
+
+// Resolves "user[:group]" (names or numeric IDs) against /etc/passwd and
+// /etc/group. Must be called in the container's root so that the image's
+// files are used.
+pub fn resolve_user(spec: &str) -> Result<User> {
+    let mut parts = spec.splitn(2, ':');
+    let user = parts.next().unwrap_or("");
+    let group = parts.next();
+
+    let passwd = fs::read_to_string("/etc/passwd").unwrap_or_default();
+    let passwd_entry = passwd
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .filter(|fields| fields.len() >= 4)
+        .find(|fields| fields[0] == user || fields[2] == user);
+
+    let name = passwd_entry.as_ref().map(|fields| fields[0]);
+    let (uid, mut gid) = match (&passwd_entry, user.parse::<u32>()) {
+        (Some(fields), _) => (fields[2].parse::<u32>()?, fields[3].parse::<u32>()?),
+        (None, Ok(uid)) => (uid, 0),
+        (None, Err(_)) if user.is_empty() => (0, 0),
+        (None, Err(_)) => {
+            return Err(anyhow!(
+                "unable to find user {}: no matching entries in passwd file",
+                user
+            ))
+        }
+    };
+
+    if let Some(group) = group {
+        gid = resolve_group(group)?;
+    }
+
+    let groups = match name {
+        Some(name) => supplementary_groups(name)?,
+        None => Vec::new(),
+    };
+
+    Ok(User {
+        uid: Uid::from_raw(uid),
+        gid: Gid::from_raw(gid),
+        groups: groups,
+    })
+}
+
+// The groups which list the user as a member in /etc/group
+// ("name:password:gid:user1,user2").
+fn supplementary_groups(name: &str) -> Result<Vec<Gid>> {
+    let groups = fs::read_to_string("/etc/group").unwrap_or_default();
+    let mut gids = Vec::new();
+    for fields in groups
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .filter(|fields| fields.len() >= 4)
+    {
+        if fields[3].split(',').any(|member| member.trim() == name) {
+            gids.push(Gid::from_raw(fields[2].parse()?));
+        }
+    }
+    Ok(gids)
+}
+
+fn resolve_group(group: &str) -> Result<u32> {
+    let groups = fs::read_to_string("/etc/group").unwrap_or_default();
+    let group_entry = groups
+        .lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .filter(|fields| fields.len() >= 3)
+        .find(|fields| fields[0] == group || fields[2] == group);
+
+    match (group_entry, group.parse::<u32>()) {
+        (Some(fields), _) => Ok(fields[2].parse()?),
+        (None, Ok(gid)) => Ok(gid),
+        (None, Err(_)) => Err(anyhow!(
+            "unable to find group {}: no matching entries in group file",
+            group
+        )),
+    }
+}
+
+// The groups are set first, since that needs root. The supplementary groups
+// replace the ones inherited from rocker, unless setgroups() has been disabled
+// in a rootless container's user namespace, where only root is mapped.
+pub fn switch_user(user: &User) -> Result<()> {
+    let setgroups_denied = fs::read_to_string("/proc/self/setgroups")
+        .map(|s| s.trim() == "deny")
+        .unwrap_or(false);
+    if !setgroups_denied {
+        setgroups(&user.groups)?;
+    }
+    setgid(user.gid)?;
+    setuid(user.uid)?;
+    Ok(())
+}
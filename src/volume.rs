@@ -0,0 +1,234 @@
+use std::{
+    fs::{self, create_dir_all, Metadata},
+    os::unix::fs::{symlink, FileTypeExt, MetadataExt},
+    path::Path,
+};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use hex::encode;
+use nix::{
+    sys::stat::{mknod, Mode, SFlag},
+    unistd::{fchownat, FchownatFlags, Gid, Uid},
+};
+use rand::Rng;
+use regex::Regex;
+use serde::Serialize;
+
+use crate::{
+    container::fetch_containers,
+    db::{open_db, volumes_key},
+    mount::resolve_in_root,
+    rootless::{is_rootless, run_in_user_namespace},
+    ROCKER_VOLUMES_PATH,
+};
+
+#[derive(Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct Volume {
+    name: String,
+    driver: String,
+    mountpoint: String,
+    created_at: String,
+}
+
+pub fn volume_mountpoint(name: &str) -> String {
+    format!("{}/{}/_data", *ROCKER_VOLUMES_PATH, name)
+}
+
+pub fn is_valid_volume_name(name: &str) -> bool {
+    let re = Regex::new(r"^[a-zA-Z0-9][a-zA-Z0-9_.-]+$").unwrap();
+    re.is_match(name)
+}
+
+// Creates the volume unless it already exists and returns its mountpoint.
+pub fn ensure_volume(name: &str) -> Result<String> {
+    if !is_valid_volume_name(name) {
+        return Err(anyhow!("Invalid volume name: {}", name));
+    }
+
+    let db = open_db()?;
+    if db.get(volumes_key(name))?.is_none() {
+        create_dir_all(volume_mountpoint(name))?;
+        db.insert(volumes_key(name), Utc::now().to_rfc3339().as_str())?;
+    }
+    Ok(volume_mountpoint(name))
+}
+
+pub fn create_volume(name: Option<String>) -> Result<()> {
+    let name = match name {
+        Some(name) => name,
+        None => encode(rand::thread_rng().gen::<[u8; 32]>()),
+    };
+    ensure_volume(&name)?;
+    println!("{}", name);
+    Ok(())
+}
+
+pub fn print_volumes() -> Result<()> {
+    println!("DRIVER\tVOLUME NAME");
+
+    for volume in fetch_volumes()? {
+        println!("{}\t{}", volume.driver, volume.name);
+    }
+    Ok(())
+}
+
+pub fn inspect_volumes(names: &[String]) -> Result<()> {
+    let volumes = fetch_volumes()?;
+    let mut found = Vec::new();
+    for name in names {
+        match volumes.iter().find(|v| &v.name == name) {
+            Some(volume) => found.push(volume),
+            None => return Err(anyhow!("No such volume: {}", name)),
+        }
+    }
+
+    println!("{}", serde_json::to_string_pretty(&found)?);
+    Ok(())
+}
+
+fn fetch_volumes() -> Result<Vec<Volume>> {
+    let mut volumes = Vec::new();
+
+    let db = open_db()?;
+    for entry in db.scan_prefix(volumes_key("")) {
+        let (key, created_at) = entry?;
+        let key = String::from_utf8(key.to_vec())?;
+        let name = key[volumes_key("").len()..].to_string();
+
+        volumes.push(Volume {
+            mountpoint: volume_mountpoint(&name),
+            name: name,
+            driver: "local".to_string(),
+            created_at: String::from_utf8(created_at.to_vec())?,
+        })
+    }
+
+    Ok(volumes)
+}
+
+// Volumes which are mounted by any container, including stopped ones,
+// can't be removed.
+pub fn remove_volumes(names: &[String]) -> Result<()> {
+    let containers = fetch_containers()?;
+    for name in names {
+        let db = open_db()?;
+        if db.get(volumes_key(name))?.is_none() {
+            println!("No such volume: {}", name);
+            continue;
+        }
+        drop(db);
+
+        let users: Vec<&str> = containers
+            .iter()
+            .filter(|c| c.mounts.iter().any(|m| m.name.as_deref() == Some(name)))
+            .map(|c| c.id.as_str())
+            .collect();
+        if !users.is_empty() {
+            println!("volume is in use by container: {}", users.join(", "));
+            continue;
+        }
+
+        let path = format!("{}/{}", *ROCKER_VOLUMES_PATH, name);
+        if is_rootless() {
+            run_in_user_namespace(|| Ok(fs::remove_dir_all(&path)?))?;
+        } else {
+            fs::remove_dir_all(&path)?;
+        }
+        let db = open_db()?;
+        db.remove(volumes_key(name))?;
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+// Like Docker, an empty volume is populated with what the image has at the
+// path the volume is mounted on. The path is resolved in `rootfs`, and only a
+// real directory in it is copied.
+pub fn populate_volume(mountpoint: &str, rootfs: &str, destination: &str) -> Result<()> {
+    if fs::read_dir(mountpoint)?.next().is_some() {
+        return Ok(());
+    }
+    let image_path = resolve_in_root(Path::new(rootfs), destination, false)?;
+    let metadata = match fs::symlink_metadata(&image_path) {
+        Ok(metadata) if metadata.is_dir() && image_path.starts_with(rootfs) => metadata,
+        _ => return Ok(()),
+    };
+    copy_dir(&image_path, Path::new(mountpoint))?;
+    copy_attributes(&metadata, Path::new(mountpoint))
+}
+
+// Copies the contents of `src` into `dst`, preserving modes, ownership and
+// symlinks. Symlinks already in `dst` are replaced rather than followed, so
+// that nothing is written outside of it.
+pub fn copy_dir(src: &Path, dst: &Path) -> Result<()> {
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let src_path = entry.path();
+        let dst_path = dst.join(entry.file_name());
+        let metadata = fs::symlink_metadata(&src_path)?;
+
+        if fs::symlink_metadata(&dst_path).map_or(false, |m| m.file_type().is_symlink()) {
+            fs::remove_file(&dst_path)?;
+        }
+
+        if metadata.is_dir() {
+            create_dir_all(&dst_path)?;
+            copy_dir(&src_path, &dst_path)?;
+        } else if !copy_file(&src_path, &metadata, &dst_path)? {
+            continue;
+        }
+        copy_attributes(&metadata, &dst_path)?;
+    }
+    Ok(())
+}
+
+// Copies anything but a directory: symlinks as they are, and FIFOs and devices
+// by creating new ones rather than reading from them. Returns false for what
+// isn't copied: sockets and overlay whiteouts.
+pub fn copy_file(src: &Path, metadata: &Metadata, dst: &Path) -> Result<bool> {
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        symlink(fs::read_link(src)?, dst)?;
+    } else if file_type.is_file() {
+        fs::copy(src, dst)?;
+    } else if file_type.is_fifo()
+        || file_type.is_block_device()
+        || (file_type.is_char_device() && !is_whiteout(metadata))
+    {
+        if fs::symlink_metadata(dst).is_ok() {
+            fs::remove_file(dst)?;
+        }
+        mknod(
+            dst,
+            SFlag::from_bits_truncate(metadata.mode()),
+            Mode::from_bits_truncate(metadata.mode()),
+            metadata.rdev(),
+        )?;
+    } else {
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+// A stopped container's upperdir has a character device 0:0 for each file
+// the container has removed from the image.
+pub fn is_whiteout(metadata: &Metadata) -> bool {
+    metadata.file_type().is_char_device() && metadata.rdev() == 0
+}
+
+pub fn copy_attributes(metadata: &Metadata, dst: &Path) -> Result<()> {
+    fchownat(
+        None,
+        dst,
+        Some(Uid::from_raw(metadata.uid())),
+        Some(Gid::from_raw(metadata.gid())),
+        FchownatFlags::NoFollowSymlink,
+    )?;
+    // Symlinks don't have modes of their own.
+    if !metadata.file_type().is_symlink() {
+        fs::set_permissions(dst, metadata.permissions())?;
+    }
+    Ok(())
+}